@@ -0,0 +1,25 @@
+use nalgebra::Isometry3;
+use r2r_transforms::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+
+pub static TREE_SIZES: [usize; 3] = [100, 1000, 4000];
+
+// A random tree where every frame hangs from world or from a frame made before it
+pub fn random_tree(size: usize) -> HashMap<String, TransformStamped> {
+    let mut rng = StdRng::seed_from_u64(42);
+    (0..size)
+        .map(|i| {
+            let parent_frame_id = match rng.gen_range(0..=i) {
+                0 => "world".to_string(),
+                j => format!("frame_{}", j - 1),
+            };
+            let mut transform = TransformStamped::default();
+            transform.parent_frame_id = parent_frame_id;
+            transform.child_frame_id = format!("frame_{i}");
+            transform.transform = Isometry3::translation(rng.gen(), rng.gen(), rng.gen());
+            (transform.child_frame_id.clone(), transform)
+        })
+        .collect()
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::{random_tree, TREE_SIZES};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use r2r_transforms::*;
+use std::sync::{Arc, Mutex};
+
+static LOOKED_UP_FRAMES: usize = 100;
+
+// The world poses of a set of frames, asked for again and again like a planner does
+fn bench_world_poses(c: &mut Criterion) {
+    let mut group = c.benchmark_group("world_poses");
+    for size in TREE_SIZES {
+        let buffer = random_tree(size);
+        let names: Vec<String> = (0..LOOKED_UP_FRAMES).map(|i| format!("frame_{}", size - 1 - i)).collect();
+        let server = SpaceTreeServer::with_store("bench", MemoryStore::from_buffer(&Arc::new(Mutex::new(buffer.clone()))));
+
+        group.bench_with_input(BenchmarkId::new("cached", size), &names, |b, names| {
+            b.iter(|| {
+                for name in names {
+                    black_box(server.lookup_transform("world", name).unwrap());
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("walked", size), &names, |b, names| {
+            b.iter(|| {
+                for name in names {
+                    black_box(lookup_transform_in_tree("world", name, &server.store.snapshot()).unwrap());
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("through_root", size), &names, |b, names| {
+            b.iter(|| {
+                for name in names {
+                    black_box(lookup_transform_in_buffer("world", name, "world", &buffer).unwrap());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_world_poses);
+criterion_main!(benches);
@@ -0,0 +1,121 @@
+mod common;
+
+use common::{random_tree, TREE_SIZES};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use r2r_transforms::*;
+use std::collections::HashMap;
+
+// Walking down the tree the way it was done before the index, scanning the buffer for the children of every frame
+fn walk_with_scan(root: &str, buffer: &HashMap<String, TransformStamped>) -> usize {
+    let mut stack = vec![root.to_string()];
+    let mut count = 0;
+    while let Some(frame) = stack.pop() {
+        count += 1;
+        stack.extend(get_frame_children(&frame, buffer).into_iter().map(|(name, _)| name));
+    }
+    count
+}
+
+fn walk_with_index(root: &str, children: &ChildrenIndex) -> usize {
+    let mut stack = vec![root];
+    let mut count = 0;
+    while let Some(frame) = stack.pop() {
+        count += 1;
+        stack.extend(children.get(frame).into_iter().flatten().map(|name| name.as_str()));
+    }
+    count
+}
+
+fn bench_walk_down(c: &mut Criterion) {
+    let mut group = c.benchmark_group("walk_down");
+    for size in TREE_SIZES {
+        let buffer = random_tree(size);
+        let children = build_children_index(&buffer);
+        group.bench_with_input(BenchmarkId::new("scan", size), &buffer, |b, buffer| {
+            b.iter(|| walk_with_scan(black_box("world"), buffer))
+        });
+        group.bench_with_input(BenchmarkId::new("index", size), &children, |b, children| {
+            b.iter(|| walk_with_index(black_box("world"), children))
+        });
+    }
+    group.finish();
+}
+
+fn bench_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+    for size in TREE_SIZES {
+        let buffer = random_tree(size);
+        let children = build_children_index(&buffer);
+        let last = format!("frame_{}", size - 1);
+        group.bench_with_input(BenchmarkId::new("root_to_child", size), &buffer, |b, buffer| {
+            b.iter(|| root_to_child(black_box(&last), "world", buffer).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("root_to_child_indexed", size), &buffer, |b, buffer| {
+            b.iter(|| root_to_child_indexed(black_box(&last), "world", buffer, &children).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("in_tree", size), &buffer, |b, buffer| {
+            b.iter(|| lookup_transform_in_tree(black_box("frame_0"), black_box(&last), buffer).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_cycles(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cycles");
+    for size in TREE_SIZES {
+        let buffer = random_tree(size);
+        let children = build_children_index(&buffer);
+        group.bench_with_input(BenchmarkId::new("is_cyclic_all", size), &buffer, |b, buffer| {
+            b.iter(|| is_cyclic_all(black_box(buffer)))
+        });
+        group.bench_with_input(BenchmarkId::new("find_cyclic_frame", size), &buffer, |b, buffer| {
+            b.iter(|| find_cyclic_frame(black_box(buffer), &children))
+        });
+    }
+    group.finish();
+}
+
+// Adding one frame to a large tree, merging and cloning the whole tree for the check as it was done before
+fn bench_cycle_check(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cycle_check");
+    let external = HashMap::new();
+    for size in TREE_SIZES {
+        let buffer = random_tree(size);
+        let mut transform = buffer[&format!("frame_{}", size - 1)].clone();
+        transform.child_frame_id = "new_frame".to_string();
+        transform.parent_frame_id = format!("frame_{}", size - 1);
+        group.bench_with_input(BenchmarkId::new("check_would_produce_cycle", size), &buffer, |b, buffer| {
+            b.iter(|| check_would_produce_cycle(black_box(&transform), buffer))
+        });
+        group.bench_with_input(BenchmarkId::new("would_close_cycle", size), &buffer, |b, buffer| {
+            b.iter(|| would_close_cycle("new_frame", black_box(&transform.parent_frame_id), buffer, &external))
+        });
+    }
+    group.finish();
+}
+
+// Loading a whole tree in one batch, like a scenario or a URDF
+fn bench_apply_changes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("apply_changes");
+    group.sample_size(10);
+    for size in TREE_SIZES {
+        let buffer = random_tree(size);
+        group.bench_with_input(BenchmarkId::new("load", size), &buffer, |b, buffer| {
+            b.iter_batched(
+                || {
+                    let server = SpaceTreeServer::new("bench");
+                    for (name, transform) in buffer {
+                        server.insert_transform(name, transform.clone()).unwrap();
+                    }
+                    server
+                },
+                |server| server.apply_changes(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_walk_down, bench_lookup, bench_cycles, bench_cycle_check, bench_apply_changes);
+criterion_main!(benches);
@@ -4,7 +4,8 @@ use log::*;
 use r2r::Context;
 use r2r_transforms::*;
 use serde_json::Value;
-use tokio::time::{Duration, Instant};
+use std::time::SystemTime;
+use tokio::time::Duration;
 
 pub static VISUALIZE_TREE_REFRESH_RATE: u64 = 100; // milliseconds
 
@@ -68,7 +69,7 @@ pub async fn space_tree_manipulation_example(
 
 
     tokio::time::sleep(Duration::from_millis(500)).await;
-    buffer.load_scenario(&path, false);
+    buffer.load_scenario(&path, false)?;
     buffer.apply_changes();
 
     println!("Open RViz for visualizing the ROS connection.");
@@ -79,95 +80,103 @@ pub async fn space_tree_manipulation_example(
     tokio::time::sleep(Duration::from_millis(500)).await;
     let new_transform = TransformStamped {
         active: true,
-        time_stamp: Instant::now(),
-        parent_frame_id: "frame_1".to_string(),
-        child_frame_id: "asdfasdf".to_string(),
-        transform: json_transform_to_isometry(JsonTransform::default()),
+        time_stamp: SystemTime::now(),
+        parent_frame_id: "frame_5".to_string(),
+        child_frame_id: "frame_6".to_string(),
+        transform: json_transform_to_isometry(JsonTransform::default()).unwrap(),
         metadata: Value::default()
     };
     
 
     tokio::time::sleep(Duration::from_millis(5000)).await;
 
-    buffer.insert_transform("asdfasdf", new_transform.clone());
+    buffer.insert_transform("frame_6", new_transform.clone())?;
     buffer.apply_changes();
 
     // let _ = visualize_tree_once(&buffer);
     tokio::time::sleep(Duration::from_millis(500)).await;
 
-    buffer.insert_transform("frame_7", new_transform);
+    buffer.insert_transform(
+        "frame_7",
+        TransformStamped {
+            child_frame_id: "frame_7".to_string(),
+            ..new_transform
+        },
+    )?;
     buffer.apply_changes();
 
     // let _ = visualize_tree_once(&buffer);
     tokio::time::sleep(Duration::from_millis(500)).await;
 
-    buffer.move_transform("frame_6", json_transform_to_isometry(JsonTransform::default()));
+    buffer.move_transform("frame_6", json_transform_to_isometry(JsonTransform::default()).unwrap())?;
     buffer.apply_changes();
 
     // let _ = visualize_tree_once(&buffer);
 
-    buffer.remove_transform("frame_7");
+    buffer.remove_transform("frame_7")?;
     buffer.apply_changes();
 
     // let _ = visualize_tree_once(&buffer);
     tokio::time::sleep(Duration::from_millis(500)).await;
 
-    buffer.rename_transform("frame_6", "frame_8");
+    buffer.rename_transform("frame_6", "frame_8")?;
     buffer.apply_changes();
 
     // let _ = visualize_tree_once(&buffer);
     tokio::time::sleep(Duration::from_millis(500)).await;
 
-    buffer.reparent_transform("frame_6", "child_7");
+    buffer
+        .reparent_transform("frame_6", "child_7")
+        .expect_err("frame_6 was renamed to frame_8, so it can no longer be reparented");
     buffer.apply_changes();
 
     // let _ = visualize_tree_once(&buffer);
     tokio::time::sleep(Duration::from_millis(500)).await;
 
-    buffer.clone_transform("frame_6", "child_7");
+    buffer.clone_transform("frame_8", "child_7")?;
     buffer.apply_changes();
 
     // let _ = visualize_tree_once(&buffer);
     tokio::time::sleep(Duration::from_millis(500)).await;
 
-    buffer.clone_transform("frame_8", "frame_9");
+    buffer.clone_transform("frame_8", "frame_9")?;
     buffer.apply_changes();
 
     // let _ = visualize_tree_once(&buffer);
     tokio::time::sleep(Duration::from_millis(500)).await;
 
-    buffer.reparent_transform("frame_8", "child_7");
+    buffer.reparent_transform("frame_8", "child_7")?;
     buffer.apply_changes();
 
     // let _ = visualize_tree_once(&buffer);
     tokio::time::sleep(Duration::from_millis(500)).await;
 
-    buffer.reparent_transform("frame_3", "frame_8");
+    buffer.reparent_transform("frame_3", "frame_8")?;
     buffer.apply_changes();
 
     // let _ = visualize_tree_once(&buffer);
     tokio::time::sleep(Duration::from_millis(500)).await;
 
-    buffer.reparent_transform("frame_8", "world");
+    buffer.reparent_transform("frame_8", "world")?;
     buffer.apply_changes();
 
     // let _ = visualize_tree_once(&buffer);
     tokio::time::sleep(Duration::from_millis(500)).await;
 
-    buffer.remove_transform("frame_9");
+    buffer.remove_transform("frame_9")?;
     buffer.apply_changes();
 
     // let _ = visualize_tree_once(&buffer);
     tokio::time::sleep(Duration::from_millis(500)).await;
 
-    buffer.remove_transform("frame_5");
+    buffer.remove_transform("frame_5")?;
     buffer.apply_changes();
 
     // let _ = visualize_tree_once(&buffer);
     tokio::time::sleep(Duration::from_millis(500)).await;
 
 
-    println!("{:?}", buffer.global_buffer.lock().unwrap().clone());
+    println!("{:?}", buffer.get_global_transforms());
 
     // buffer.delete_all_transforms();
     // buffer.apply_changes();
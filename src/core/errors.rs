@@ -0,0 +1,124 @@
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+/// Represents the reasons a transform lookup or update can fail.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransformError {
+    FrameNotFound(String),
+    AlreadyExists(String),
+    NameMismatch { name: String, child_frame_id: String },
+    CycleDetected(String),
+    ChainTooLong { parent_frame_id: String, child_frame_id: String },
+    Disconnected { parent_frame_id: String, child_frame_id: String },
+    NoSamples(String),
+    ExtrapolationIntoPast { frame_id: String, by: Duration },
+    ExtrapolationIntoFuture { frame_id: String, by: Duration },
+    Io(String),
+    Parse(String),
+    InvalidJoint { joint: String, reason: String },
+    AmbiguousJoint { joint: String, scopes: Vec<String> },
+    InvalidScenario(Vec<FrameDiagnostic>),
+    RolledBack,
+}
+
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransformError::FrameNotFound(frame_id) => {
+                write!(f, "Frame '{frame_id}' doesn't exist.")
+            }
+            TransformError::AlreadyExists(frame_id) => {
+                write!(f, "Frame '{frame_id}' already exists.")
+            }
+            TransformError::NameMismatch { name, child_frame_id } => write!(
+                f,
+                "Transform name '{name}' doesn't match the child_frame_id '{child_frame_id}', they should be the same."
+            ),
+            TransformError::CycleDetected(frame_id) => {
+                write!(f, "Frame '{frame_id}' is part of a cycle.")
+            }
+            TransformError::ChainTooLong { parent_frame_id, child_frame_id } => write!(
+                f,
+                "The transform chain between '{parent_frame_id}' and '{child_frame_id}' is longer than the allowed maximum."
+            ),
+            TransformError::Disconnected { parent_frame_id, child_frame_id } => write!(
+                f,
+                "No transform chain exists between '{parent_frame_id}' and '{child_frame_id}'."
+            ),
+            TransformError::NoSamples(frame_id) => {
+                write!(f, "No samples of the frame '{frame_id}' are buffered.")
+            }
+            TransformError::ExtrapolationIntoPast { frame_id, by } => write!(
+                f,
+                "Lookup would require extrapolation of '{frame_id}' {by:?} before the oldest buffered sample."
+            ),
+            TransformError::ExtrapolationIntoFuture { frame_id, by } => write!(
+                f,
+                "Lookup would require extrapolation of '{frame_id}' {by:?} past the newest buffered sample."
+            ),
+            TransformError::Io(info) => write!(f, "{info}"),
+            TransformError::Parse(info) => write!(f, "{info}"),
+            TransformError::InvalidJoint { joint, reason } => {
+                write!(f, "Can't move joint '{joint}': {reason}")
+            }
+            TransformError::AmbiguousJoint { joint, scopes } => write!(
+                f,
+                "Joint '{joint}' exists in the URDFs attached to {scopes:?}, the scope has to be given."
+            ),
+            TransformError::InvalidScenario(diagnostics) => {
+                let errors: Vec<String> = diagnostics
+                    .iter()
+                    .filter(|d| d.severity == Severity::Error)
+                    .map(|d| d.to_string())
+                    .collect();
+                write!(f, "The scenario is invalid: {}", errors.join(" "))
+            }
+            TransformError::RolledBack => write!(
+                f,
+                "Not applied because another update in the transaction was rejected."
+            ),
+        }
+    }
+}
+
+impl Error for TransformError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in a frame description while validating a scenario.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameDiagnostic {
+    pub path: String,
+    pub field: String,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl FrameDiagnostic {
+    pub fn error(path: &str, field: &str, message: &str) -> FrameDiagnostic {
+        FrameDiagnostic {
+            path: path.to_string(),
+            field: field.to_string(),
+            severity: Severity::Error,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn warning(path: &str, field: &str, message: &str) -> FrameDiagnostic {
+        FrameDiagnostic {
+            severity: Severity::Warning,
+            ..FrameDiagnostic::error(path, field, message)
+        }
+    }
+}
+
+impl fmt::Display for FrameDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} in '{}', field '{}': {}", self.severity, self.path, self.field, self.message)
+    }
+}
@@ -1,2 +1,4 @@
 pub mod structs;
-pub mod space_tree;
\ No newline at end of file
+pub mod space_tree;
+pub mod store;
+pub mod errors;
\ No newline at end of file
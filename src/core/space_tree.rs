@@ -1,389 +1,1311 @@
 use nalgebra::Isometry3;
 use crate::*;
 use std::{
-    collections::HashMap, sync::{Arc, Mutex}
+    collections::{HashMap, VecDeque}, sync::{Arc, Mutex}, time::Duration
 };
+use serde_json::Value;
+use tokio::sync::{broadcast, mpsc};
+use std::time::SystemTime;
 use log;
 
-/// Represents the type of update to perform on a transform.
-#[derive(Clone, Debug)]
-enum UpdateType {
-    Add,
-    Move,
-    Remove,
-    Rename,
-    Reparent,
-    Clone,
-    DeleteAll,
-}
-
-/// Holds information about a transform update.
-#[derive(Clone, Debug)]
-struct UpdateContext {
-    update_type: UpdateType,
-    transform: TransformStamped,
-}
-
 /// A server that maintains a spatial tree buffer of transforms.
+/// The committed frames are kept in a store, which is in memory unless another one is given.
 #[derive(Clone)]
-pub struct SpaceTreeServer {
+pub struct SpaceTreeServer<S: TransformStore = MemoryStore> {
     pub name: String,
     // These are the transforms that we are in control of
-    pub local_buffer: Arc<Mutex<HashMap<String, TransformStamped>>>,
+    pub store: S,
     // // These are all the transforms that exist when connected to the ROS world via /tf and /tf_static
     // pub global_buffer: Arc<Mutex<HashMap<String, TransformStamped>>>,
-    // We are only allowed to perform updates ont the local buffer
-    pending_updates: Arc<Mutex<HashMap<String, UpdateContext>>>,
+    // We are only allowed to perform updates ont the local buffer, they are applied in the order they were queued.
+    // Everyone who commits holds it from the snapshot to the commit, so that no commit is lost in between.
+    pub(crate) pending_updates: Arc<Mutex<Vec<UpdateContext>>>,
+    // Time-sorted samples of every frame, going back at most cache_time
+    pub history: Arc<Mutex<HashMap<String, VecDeque<TransformStamped>>>>,
+    pub cache_time: Duration,
+    // If false, lookups past the newest sample of a frame fail instead of holding it
+    pub allow_extrapolation: bool,
+    // Inverse of the last committed batches, bounded by undo_depth
+    pub undo_stack: Arc<Mutex<VecDeque<BufferDiff>>>,
+    pub redo_stack: Arc<Mutex<Vec<BufferDiff>>>,
+    pub undo_depth: usize,
+    // Committed changes are sent to everyone who subscribed
+    pub events: broadcast::Sender<SpaceTreeEvent>,
+    // How rotations are written when the scenario is saved
+    pub rotation_encoding: RotationEncoding,
+    // The joint frames of the loaded URDFs, so that joint positions don't have to search the buffer
+    pub joints: Arc<Mutex<JointMap>>,
 }
 
 impl SpaceTreeServer {
     pub fn new(name: &str) -> Self {
+        Self::with_store(name, MemoryStore::new())
+    }
+}
+
+impl<S: TransformStore> SpaceTreeServer<S> {
+    /// Creates a server that commits its frames into the given store.
+    pub fn with_store(name: &str, store: S) -> Self {
         Self {
             name: name.to_string(),
-            local_buffer: Arc::new(Mutex::new(HashMap::new())),
+            store,
             // global_buffer: Arc::new(Mutex::new(HashMap::new())),
-            pending_updates: Arc::new(Mutex::new(HashMap::new())),
+            pending_updates: Arc::new(Mutex::new(Vec::new())),
+            history: Arc::new(Mutex::new(HashMap::new())),
+            cache_time: Duration::from_millis(TRANSFORM_CACHE_TIME),
+            allow_extrapolation: true,
+            undo_stack: Arc::new(Mutex::new(VecDeque::new())),
+            redo_stack: Arc::new(Mutex::new(Vec::new())),
+            undo_depth: UNDO_DEPTH as usize,
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY as usize).0,
+            rotation_encoding: RotationEncoding::default(),
+            joints: Arc::new(Mutex::new(JointMap::new())),
         }
     }
 
-    pub fn load_scenario(&self, scenario_path: &str, overlay: bool) {
-        match list_frames_in_dir(scenario_path) {
-            Ok(list) => {
-                let frames = load_new_scenario(&list);
-                if overlay {
-                    frames.values().for_each(|frame| Self::insert_transform(&self, &frame.child_frame_id, frame.clone()));
-                } else {
-                    let buffer = self.local_buffer.lock().unwrap();
-                    frames
-                        .values()
-                        .filter(|frame| buffer
-                        .get(&frame.child_frame_id) == None)
-                        .for_each(|frame| Self::insert_transform(&self, &frame.child_frame_id, frame.clone()));
+    /// Sets how far back in time the transform history is kept.
+    pub fn with_cache_time(mut self, cache_time: Duration) -> Self {
+        self.cache_time = cache_time;
+        self
+    }
+
+    /// Sets whether lookups at a time past the newest sample of a frame hold that sample or fail.
+    pub fn with_extrapolation(mut self, allow_extrapolation: bool) -> Self {
+        self.allow_extrapolation = allow_extrapolation;
+        self
+    }
+
+    /// Sets how many committed batches can be undone.
+    pub fn with_undo_depth(mut self, undo_depth: usize) -> Self {
+        self.undo_depth = undo_depth;
+        self
+    }
+
+    /// Sets the rotation encoding used by save_scenario and save_frames.
+    pub fn with_rotation_encoding(mut self, rotation_encoding: RotationEncoding) -> Self {
+        self.rotation_encoding = rotation_encoding;
+        self
+    }
+
+    /// Loads the frames from a scenario directory, or from a single JSON or YAML scenario file.
+    pub fn load_scenario(&self, scenario_path: &str, overlay: bool) -> Result<(), TransformError> {
+        let frames = load_scenario_from_path(scenario_path)?;
+        self.queue_scenario(frames, overlay)
+    }
+
+    /// Same as load_scenario, but every frame is validated first and nothing is loaded if any of them is invalid.
+    /// Returns the warnings about the frames that were loaded anyway.
+    pub fn load_scenario_strict(&self, scenario_path: &str, overlay: bool) -> Result<Vec<FrameDiagnostic>, TransformError> {
+        let (frames, warnings) = load_scenario_strict(scenario_path)?;
+        self.queue_scenario(frames, overlay)?;
+        Ok(warnings)
+    }
+
+    fn queue_scenario(&self, frames: HashMap<String, TransformStamped>, overlay: bool) -> Result<(), TransformError> {
+        if overlay {
+            frames.values().try_for_each(|frame| self.insert_transform(&frame.child_frame_id, frame.clone()))
+        } else {
+            let buffer = self.store.snapshot();
+            frames
+                .values()
+                .filter(|frame| !buffer.contains_key(&frame.child_frame_id))
+                .try_for_each(|frame| self.insert_transform(&frame.child_frame_id, frame.clone()))
+        }
+    }
+
+    /// Inserts the joints of a URDF file as frames. If a parent frame is given,
+    /// the root links of the robot are attached to it. The joints are scoped by the parent
+    /// frame, or by the first root link if there is none, see set_joint_positions_in.
+    pub fn load_urdf(&self, urdf_path: &str, parent_frame_id: Option<&str>) -> Result<(), TransformError> {
+        let frames = load_urdf(urdf_path)?;
+        let mut roots: Vec<&String> = frames
+            .values()
+            .map(|frame| &frame.parent_frame_id)
+            .filter(|parent| !frames.contains_key(*parent))
+            .collect();
+        roots.sort();
+        roots.dedup();
+        let scope = parent_frame_id.or(roots.first().map(|root| root.as_str())).unwrap_or_default();
+        add_urdf_joints(&mut self.joints.lock().unwrap(), scope, &frames);
+
+        if let Some(parent_frame_id) = parent_frame_id {
+            roots.into_iter().try_for_each(|root| {
+                let mut transform = TransformStamped::default();
+                transform.active = false;
+                transform.parent_frame_id = parent_frame_id.to_string();
+                transform.child_frame_id = root.to_string();
+                self.insert_transform(root, transform)
+            })?;
+        }
+        frames.values().try_for_each(|frame| self.insert_transform(&frame.child_frame_id, frame.clone()))
+    }
+
+    /// Moves the frames imported from movable joints to the given joint positions.
+    /// The joints that can't be moved are reported, the others are still queued.
+    /// Joint names that are used by several loaded URDFs are reported as ambiguous.
+    pub fn set_joint_positions(&self, positions: &HashMap<String, f64>) -> Result<(), Vec<TransformError>> {
+        self.queue_joint_positions(None, positions)
+    }
+
+    /// Same as set_joint_positions, but only for the joints of the URDF loaded into the scope.
+    pub fn set_joint_positions_in(&self, scope: &str, positions: &HashMap<String, f64>) -> Result<(), Vec<TransformError>> {
+        self.queue_joint_positions(Some(scope), positions)
+    }
+
+    fn queue_joint_positions(&self, scope: Option<&str>, positions: &HashMap<String, f64>) -> Result<(), Vec<TransformError>> {
+        let (poses, mut errors) = self.joint_poses(scope, positions);
+        errors.extend(
+            poses
+                .into_iter()
+                .filter_map(|(name, pose)| self.move_transform(&name, pose).err()),
+        );
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Moves the joint frames to the given joint positions and commits them right away, for joint states
+    /// that stream in at a high rate. This doesn't touch the pending updates, isn't undoable and sends
+    /// no events, so the joint motion doesn't push the user's edits off the undo stack.
+    /// Joints whose frames aren't applied yet are reported as not found. Stores that persist
+    /// the frames, like the FileStore, don't write these commits, see TransformStore::commit_transient.
+    pub fn commit_joint_positions(&self, scope: Option<&str>, positions: &HashMap<String, f64>) -> Result<(), Vec<TransformError>> {
+        let (poses, mut errors) = self.joint_poses(scope, positions);
+
+        // Every committer holds the pending updates from its snapshot to its commit, so none can commit in between
+        let _pending_updates = self.pending_updates.lock().unwrap();
+        let old_buffer = self.store.snapshot();
+        let mut buffer = old_buffer.clone();
+        for (name, pose) in poses {
+            match buffer.get_mut(&name) {
+                Some(frame) => {
+                    frame.transform = pose;
+                    frame.time_stamp = SystemTime::now();
                 }
-                // Self::apply_changes(&self);
-            },
-            Err(_) => return
+                None => errors.push(TransformError::FrameNotFound(name)),
+            }
+        }
+        self.commit_to_store(&old_buffer, buffer, true);
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
         }
     }
 
-    pub fn insert_transform(&self, name: &str, transform: TransformStamped) {
+    // The new pose of the frame of every joint, and the joints that can't be moved
+    fn joint_poses(&self, scope: Option<&str>, positions: &HashMap<String, f64>) -> (Vec<(String, Isometry3<f64>)>, Vec<TransformError>) {
+        let joints = self.joints.lock().unwrap();
+        let mut poses = vec![];
+        let mut errors = vec![];
+        for (joint, position) in positions {
+            match resolve_joint_frame(&joints, joint, scope)
+                .and_then(|frame| Ok((frame.child_frame_id.clone(), joint_transform(frame, *position)?)))
+            {
+                Ok(pose) => poses.push(pose),
+                Err(e) => errors.push(e),
+            }
+        }
+        (poses, errors)
+    }
+
+    /// Watches the scenario directory and queues the frames of created, modified and deleted files
+    /// as pending updates, which are committed by the next apply_changes.
+    /// A report is sent for every reload, watching stops when the receiver is dropped.
+    /// The directory is polled by a task on the current tokio runtime, so this panics
+    /// if it is called outside of one.
+    pub fn watch_scenario(&self, scenario_path: &str, debounce: Duration) -> mpsc::UnboundedReceiver<ScenarioReloadReport> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let scenario_path = scenario_path.to_string();
+        let store = self.store.clone();
+        let pending_updates = self.pending_updates.clone();
+        tokio::task::spawn(async move {
+            match watch_scenario_dir(&scenario_path, debounce, &store, &pending_updates, sender).await {
+                Ok(()) => (),
+                Err(e) => log::error!(target: "r2r_transforms", "Scenario watcher failed with: '{}'.", e),
+            };
+        });
+        receiver
+    }
+
+    /// Writes the local frames into the scenario directory, or into a single scenario file
+    /// if the path ends with .json, .yaml or .yml, so that edits survive restarts.
+    pub fn save_scenario(&self, scenario_path: &str) -> Result<(), TransformError> {
+        let buffer = self.store.snapshot();
+        if is_scenario_file(scenario_path) {
+            save_scenario_file(scenario_path, &buffer, self.rotation_encoding)
+        } else {
+            save_scenario(scenario_path, &buffer, self.rotation_encoding)
+        }
+    }
+
+    /// Writes only the given local frames into the scenario directory.
+    pub fn save_frames(&self, scenario_path: &str, names: &[&str]) -> Result<(), TransformError> {
+        let buffer = self.store.snapshot();
+        let frames = names
+            .iter()
+            .map(|name| buffer.get(*name).ok_or(TransformError::FrameNotFound(name.to_string())))
+            .collect::<Result<Vec<&TransformStamped>, TransformError>>()?;
+        save_frames(scenario_path, &frames, self.rotation_encoding)
+    }
+
+    pub fn insert_transform(&self, name: &str, transform: TransformStamped) -> Result<(), TransformError> {
         let mut pending_updates = self.pending_updates.lock().unwrap();
 
-        // Add or update the pending update for the transform.
-        let update_context =
-            pending_updates
-                .entry(name.to_string())
-                .or_insert_with(|| UpdateContext {
-                    update_type: UpdateType::Add,
-                    transform: transform.clone(),
-                });
+        if name != transform.child_frame_id {
+            log::info!(
+                "Can't insert the frame '{name}', it doesn't match the child_frame_id '{}'.",
+                transform.child_frame_id
+            );
+            return Err(TransformError::NameMismatch {
+                name: name.to_string(),
+                child_frame_id: transform.child_frame_id,
+            });
+        }
 
-        update_context.update_type = UpdateType::Add;
-        update_context.transform = transform;
+        pending_updates.push(UpdateContext {
+            name: name.to_string(),
+            update_type: UpdateType::Add,
+            transform,
+        });
 
         log::info!("Pending update: Insert transform with name '{}'", name);
-        // log::info!("Pending update: Insert transform with name '{}'", name);
+        Ok(())
     }
 
-    pub fn move_transform(&self, name: &str, pose: Isometry3<f64>) {
-        let buffer = self.local_buffer.lock().unwrap();
+    pub fn move_transform(&self, name: &str, pose: Isometry3<f64>) -> Result<(), TransformError> {
+        let buffer = self.store.snapshot();
         let mut pending_updates = self.pending_updates.lock().unwrap();
+        let frames = frames_after_updates(&buffer, &pending_updates);
 
-        if !buffer.contains_key(name) {
+        if !frames.contains(name) {
             log::info!(
                 "Can't move the frame '{}' to a new pose, buffer doesn't contain it.",
                 name
             );
-            return;
+            return Err(TransformError::FrameNotFound(name.to_string()));
         }
 
         // Move the frame to a new pose
-        let update_context =
-            pending_updates
-                .entry(name.to_string())
-                .or_insert_with(|| UpdateContext {
-                    update_type: UpdateType::Move,
-                    transform: {
-                        let mut tf = TransformStamped::default();
-                        tf.transform = pose;
-                        tf
-                    },
-                });
-
-        update_context.update_type = UpdateType::Move;
-        update_context.transform.transform = pose;
+        pending_updates.push(UpdateContext {
+            name: name.to_string(),
+            update_type: UpdateType::Move,
+            transform: {
+                let mut tf = TransformStamped::default();
+                tf.transform = pose;
+                tf
+            },
+        });
 
         log::info!("Pending update: Move transform with name '{}'", name);
+        Ok(())
     }
 
-    pub fn remove_transform(&self, name: &str) {
-        let buffer = self.local_buffer.lock().unwrap();
+    /// Replaces the whole transform of an existing frame, its parent, pose, metadata and active flag.
+    pub fn replace_transform(&self, name: &str, transform: TransformStamped) -> Result<(), TransformError> {
+        let buffer = self.store.snapshot();
         let mut pending_updates = self.pending_updates.lock().unwrap();
+        let frames = frames_after_updates(&buffer, &pending_updates);
 
-        if !buffer.contains_key(name) {
+        if name != transform.child_frame_id {
+            log::info!(
+                "Can't replace the frame '{name}', it doesn't match the child_frame_id '{}'.",
+                transform.child_frame_id
+            );
+            return Err(TransformError::NameMismatch {
+                name: name.to_string(),
+                child_frame_id: transform.child_frame_id,
+            });
+        }
+
+        if !frames.contains(name) {
+            log::info!("Can't replace the frame '{}', buffer doesn't contain it.", name);
+            return Err(TransformError::FrameNotFound(name.to_string()));
+        }
+
+        pending_updates.push(UpdateContext {
+            name: name.to_string(),
+            update_type: UpdateType::Replace,
+            transform,
+        });
+
+        log::info!("Pending update: Replace transform with name '{}'", name);
+        Ok(())
+    }
+
+    pub fn remove_transform(&self, name: &str) -> Result<(), TransformError> {
+        let buffer = self.store.snapshot();
+        let mut pending_updates = self.pending_updates.lock().unwrap();
+        let frames = frames_after_updates(&buffer, &pending_updates);
+
+        if !frames.contains(name) {
             log::info!(
                 "Can't remove the frame '{}', buffer doesn't contain it.",
                 name
             );
-            return;
+            return Err(TransformError::FrameNotFound(name.to_string()));
         }
 
-        pending_updates.insert(
-            name.to_string(),
-            UpdateContext {
-                update_type: UpdateType::Remove,
-                transform: TransformStamped::default()
-            },
-        );
+        pending_updates.push(UpdateContext {
+            name: name.to_string(),
+            update_type: UpdateType::Remove,
+            transform: TransformStamped::default()
+        });
 
         log::info!("Pending update: Remove transform with name '{}'", name);
-
+        Ok(())
     }
 
-    pub fn rename_transform(&self, name: &str, rename_to: &str) {
-        let buffer = self.local_buffer.lock().unwrap();
+    pub fn rename_transform(&self, name: &str, rename_to: &str) -> Result<(), TransformError> {
+        let buffer = self.store.snapshot();
+        let external = self.store.external();
         let mut pending_updates = self.pending_updates.lock().unwrap();
+        let frames = frames_after_updates(&buffer, &pending_updates);
+        let all_frames = all_frames_after_updates(&buffer, &external, &frames);
 
-        if !buffer.contains_key(name) {
+        if !frames.contains(name) {
             log::info!(
                 "Can't rename the frame '{}', buffer doesn't contain it.",
                 name
             );
-            return;
+            return Err(TransformError::FrameNotFound(name.to_string()));
         }
 
-        if buffer.contains_key(rename_to) {
+        if all_frames.contains(rename_to) {
             log::info!(
-                "Can't rename the frame '{name}' to '{rename_to}', buffer already contains '{rename_to}'.",
+                "Can't rename the frame '{name}' to '{rename_to}', '{rename_to}' already exists.",
                 
             );
-            return;
-        }
-
-        pending_updates.insert(
-            name.to_string(),
-            UpdateContext {
-                update_type: UpdateType::Rename,
-                transform: {
-                    let mut tf = TransformStamped::default();
-                    tf.child_frame_id = rename_to.to_string();
-                    tf
-                },
+            return Err(TransformError::AlreadyExists(rename_to.to_string()));
+        }
+
+        pending_updates.push(UpdateContext {
+            name: name.to_string(),
+            update_type: UpdateType::Rename,
+            transform: {
+                let mut tf = TransformStamped::default();
+                tf.child_frame_id = rename_to.to_string();
+                tf
             },
-        );
+        });
 
         log::info!("Pending update: Rename transform with name '{name}' to '{rename_to}'.");
+        Ok(())
+    }
 
+    /// Queues moving the frame under a new parent, keeping its pose in the world.
+    pub fn reparent_transform(&self, name: &str, reparent_to: &str) -> Result<(), TransformError> {
+        self.reparent_transform_with_mode(name, reparent_to, ReparentMode::KeepWorldPose)
     }
 
-    pub fn reparent_transform(&self, name: &str, reparent_to: &str) {
-        let local_buffer = self.local_buffer.lock().unwrap();
-        // let global_buffer = self.global_buffer.lock().unwrap();
+    /// Queues moving the frame under a new parent, placed there according to the mode.
+    pub fn reparent_transform_with_mode(&self, name: &str, reparent_to: &str, mode: ReparentMode) -> Result<(), TransformError> {
+        let local_buffer = self.store.snapshot();
+        let external = self.store.external();
         let mut pending_updates = self.pending_updates.lock().unwrap();
+        let frames = frames_after_updates(&local_buffer, &pending_updates);
+        let all_frames = all_frames_after_updates(&local_buffer, &external, &frames);
 
-        if !local_buffer.contains_key(name) {
+        if !frames.contains(name) {
             log::info!(
                 "Can't reparent the frame '{}', buffer doesn't contain it.",
                 name
             );
-            return;
+            return Err(TransformError::FrameNotFound(name.to_string()));
         }
 
-        // if !global_buffer.contains_key(reparent_to) {
-        //     log::info!(
-        //         "Can't reparent the frame '{name}' to '{reparent_to}', reparent frame '{reparent_to}' doesn't exist.",
-                
-        //     );
-        //     return;
-        // }
-
-        pending_updates.insert(
-            name.to_string(),
-            UpdateContext {
-                update_type: UpdateType::Reparent,
-                transform: {
-                    let mut tf = TransformStamped::default();
-                    tf.parent_frame_id = reparent_to.to_string();
-                    tf
-                },
-            },
-        );
+        // A root only exists as the parent of other frames
+        let is_root = local_buffer
+            .values()
+            .chain(external.values())
+            .any(|frame| frame.parent_frame_id == reparent_to);
+        if !all_frames.contains(reparent_to) && !is_root {
+            log::info!(
+                "Can't reparent the frame '{name}' to '{reparent_to}', reparent frame '{reparent_to}' doesn't exist.",
+            );
+            return Err(TransformError::FrameNotFound(reparent_to.to_string()));
+        }
 
-        log::info!("Pending update: Reparent transform with name '{name}' to '{reparent_to}'.");
+        pending_updates.push(UpdateContext {
+            name: name.to_string(),
+            update_type: UpdateType::Reparent(mode),
+            transform: {
+                let mut tf = TransformStamped::default();
+                tf.parent_frame_id = reparent_to.to_string();
+                tf
+            },
+        });
 
+        log::info!("Pending update: Reparent transform with name '{name}' to '{reparent_to}' ({:?}).", mode);
+        Ok(())
     }
 
-    pub fn clone_transform(&self, name: &str, clone_name: &str) {
-        let local_buffer = self.local_buffer.lock().unwrap();
+    pub fn clone_transform(&self, name: &str, clone_name: &str) -> Result<(), TransformError> {
+        let local_buffer = self.store.snapshot();
+        let external = self.store.external();
         let mut pending_updates = self.pending_updates.lock().unwrap();
+        let frames = frames_after_updates(&local_buffer, &pending_updates);
+        let all_frames = all_frames_after_updates(&local_buffer, &external, &frames);
 
-        if !local_buffer.contains_key(name) {
+        if !all_frames.contains(name) {
             log::info!(
-                "Can't clone the frame '{}', buffer doesn't contain it.",
+                "Can't clone the frame '{}', it doesn't exist.",
                 name
             );
-            return;
-        }
-
-        pending_updates.insert(
-            name.to_string(),
-            UpdateContext {
-                update_type: UpdateType::Clone,
-                transform: {
-                    let mut tf = TransformStamped::default();
-                    tf.child_frame_id = clone_name.to_string();
-                    tf
-                },
+            return Err(TransformError::FrameNotFound(name.to_string()));
+        }
+
+        if all_frames.contains(clone_name) {
+            log::info!(
+                "Can't clone the frame '{name}' to '{clone_name}', '{clone_name}' already exists.",
+            );
+            return Err(TransformError::AlreadyExists(clone_name.to_string()));
+        }
+
+        pending_updates.push(UpdateContext {
+            name: name.to_string(),
+            update_type: UpdateType::Clone,
+            transform: {
+                let mut tf = TransformStamped::default();
+                tf.child_frame_id = clone_name.to_string();
+                tf
             },
-        );
+        });
 
         log::info!("Pending update: Clone transform with name '{name}' to '{clone_name}'.");
-
+        Ok(())
     }
 
     pub fn delete_all_transforms(&self) {
         let mut pending_updates = self.pending_updates.lock().unwrap();
         pending_updates.clear();
 
-        pending_updates.insert(
-            "delete_all".to_string(),
-            UpdateContext {
-                update_type: UpdateType::DeleteAll,
-                transform: TransformStamped::default(),
-            },
-        );
+        pending_updates.push(UpdateContext {
+            name: "delete_all".to_string(),
+            update_type: UpdateType::DeleteAll,
+            transform: TransformStamped::default(),
+        });
 
         log::info!("Pending update: Delete all transforms.");
     }
 
-    pub fn lookup_transform(&self, parent_frame_id: &str, child_frame_id: &str) -> Option<TransformStamped> {
-        let buffer = self.local_buffer.lock().unwrap();
-        match get_tree_root(&buffer) {
-            Some(root) => {
-                lookup_transform_with_root(parent_frame_id, child_frame_id, &root, &self.local_buffer)
-            },
-            None => {
-                lookup_transform_with_root(parent_frame_id, child_frame_id, "world", &self.local_buffer)
-            }
+    /// Looks up the transform from the parent to the child frame. If both frames are in the same tree
+    /// of committed frames, it is composed from their cached poses in the root of that tree.
+    pub fn lookup_transform(&self, parent_frame_id: &str, child_frame_id: &str) -> Result<TransformStamped, TransformError> {
+        if let Some((transform, active)) = self.store.relative_pose(parent_frame_id, child_frame_id) {
+            return Ok(TransformStamped {
+                active,
+                time_stamp: SystemTime::now(),
+                parent_frame_id: parent_frame_id.to_string(),
+                child_frame_id: child_frame_id.to_string(),
+                transform,
+                metadata: Value::default(),
+            });
         }
-        
+        let buffer = self.merged_buffer();
+        lookup_transform_in_tree(parent_frame_id, child_frame_id, &buffer)
+    }
+
+    pub fn lookup_with_root(&self, parent_frame_id: &str, child_frame_id: &str, root_frame_id: &str) -> Result<TransformStamped, TransformError> {
+        let buffer = self.store.snapshot();
+        let external = self.store.external();
+        let children = merge_children_index(&self.store.children(), &buffer, &external);
+        lookup_transform_in_buffer_indexed(parent_frame_id, child_frame_id, root_frame_id, &merge_buffers(&external, &buffer), &children)
+    }
+
+    /// Looks up the transform as it was at the given time, interpolating between the history samples of the frames in the chain.
+    pub fn lookup_transform_at(&self, parent_frame_id: &str, child_frame_id: &str, time: SystemTime) -> Result<TransformStamped, TransformError> {
+        let buffer = self.merged_buffer();
+        let history = self.history.lock().unwrap().clone();
+        lookup_transform_in_tree_at(parent_frame_id, child_frame_id, time, self.allow_extrapolation, &buffer, &history)
     }
 
-    pub fn lookup_with_root(&self, parent_frame_id: &str, child_frame_id: &str, root_frame_id: &str) -> Option<TransformStamped> {
-        lookup_transform_with_root(parent_frame_id, child_frame_id, root_frame_id, &self.local_buffer)
+    /// Returns the roots of all trees, the committed frames can form several disconnected ones.
+    pub fn get_tree_roots(&self) -> Vec<String> {
+        get_tree_roots(&self.merged_buffer())
+    }
+
+    /// Returns the root of the tree that the frame belongs to.
+    pub fn get_frame_root(&self, frame_id: &str) -> Option<String> {
+        get_frame_root(frame_id, &self.merged_buffer())
     }
 
     pub fn get_local_transform_names(&self) -> Vec<String> {
-        let buffer = self.local_buffer.lock().unwrap();
+        let buffer = self.store.snapshot();
         buffer.keys().map(|k| k.to_owned()).collect::<Vec<String>>()
     }
 
     /// Applies pending updates to the transform buffer.
+    /// The updates that can be applied are committed, and the report tells which ones were rejected and why.
     /// TODO: Sort out the connection with ROS /tf
-    pub fn apply_changes(&self) {
-        let mut buffer = self.local_buffer.lock().unwrap().clone();
+    pub fn apply_changes(&self) -> ApplyReport {
         let mut pending_updates = self.pending_updates.lock().unwrap();
+        let old_buffer = self.store.snapshot();
+        let mut buffer = old_buffer.clone();
+        let external = self.store.external();
 
         if pending_updates.is_empty() {
             log::info!("No changes to apply");
-            return;
+            return ApplyReport::default();
         }
 
-        for (name, update_context) in pending_updates.iter() {
-            match update_context.update_type {
-                UpdateType::Add => {
-                    if name != &update_context.transform.child_frame_id {
-                        log::info!("Transform name '{name}' in buffer doesn't match the child_frame_id {}, they should be the same. Not added.", update_context.transform.child_frame_id);
-                    } else if let Some(_) = buffer.get(name) {
-                        log::info!("Transform '{}' already exists, not added.", name);
+        let report = apply_updates(&mut buffer, &external, &pending_updates);
+
+        pending_updates.clear();
+        self.record_undo(&old_buffer, &buffer);
+        self.commit(&old_buffer, buffer);
+        self.publish_events(events_from_report(&report));
+
+        report
+    }
+
+    /// Applies pending updates as a single transaction.
+    /// Either every update is committed, or none of them is if any update is rejected
+    /// or the resulting tree would contain a cycle.
+    pub fn apply_changes_atomic(&self) -> ApplyReport {
+        let mut pending_updates = self.pending_updates.lock().unwrap();
+        let old_buffer = self.store.snapshot();
+        let mut buffer = old_buffer.clone();
+        let external = self.store.external();
+
+        if pending_updates.is_empty() {
+            log::info!("No changes to apply");
+            return ApplyReport::default();
+        }
+
+        let mut report = apply_updates(&mut buffer, &external, &pending_updates);
+        pending_updates.clear();
+
+        // External frames can close a cycle too, so check the merged tree
+        if let Some(error) = transaction_error(&report, &merge_buffers(&external, &buffer)) {
+            log::info!("Transaction rejected with: '{error}'. None of the pending updates were applied.");
+            report.roll_back(error);
+            return report;
+        }
+
+        self.record_undo(&old_buffer, &buffer);
+        self.commit(&old_buffer, buffer);
+        self.publish_events(events_from_report(&report));
+
+        report
+    }
+
+    /// Reverts the last committed batch of updates. Returns false if there is nothing to undo.
+    pub fn undo(&self) -> bool {
+        let _pending_updates = self.pending_updates.lock().unwrap();
+        let diff = match self.undo_stack.lock().unwrap().pop_back() {
+            Some(diff) => diff,
+            None => {
+                log::info!("Nothing to undo.");
+                return false;
+            }
+        };
+
+        let old_buffer = self.store.snapshot();
+        let mut buffer = old_buffer.clone();
+        restore_buffer_state(&mut buffer, &diff.before);
+        self.commit(&old_buffer, buffer);
+        self.publish_events(events_from_diff(&diff.after, &diff.before));
+        log::info!("Undid the changes to {} frames.", diff.before.len());
+        self.redo_stack.lock().unwrap().push(diff);
+        true
+    }
+
+    /// Applies the last undone batch of updates again. Returns false if there is nothing to redo.
+    pub fn redo(&self) -> bool {
+        let _pending_updates = self.pending_updates.lock().unwrap();
+        let diff = match self.redo_stack.lock().unwrap().pop() {
+            Some(diff) => diff,
+            None => {
+                log::info!("Nothing to redo.");
+                return false;
+            }
+        };
+
+        let old_buffer = self.store.snapshot();
+        let mut buffer = old_buffer.clone();
+        restore_buffer_state(&mut buffer, &diff.after);
+        self.commit(&old_buffer, buffer);
+        self.publish_events(events_from_diff(&diff.before, &diff.after));
+        log::info!("Redid the changes to {} frames.", diff.after.len());
+        push_undo(&mut self.undo_stack.lock().unwrap(), diff, self.undo_depth);
+        true
+    }
+
+    /// Returns a receiver of the events of all changes committed from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<SpaceTreeEvent> {
+        self.events.subscribe()
+    }
+
+    pub(crate) fn publish_events(&self, events: Vec<SpaceTreeEvent>) {
+        for event in events {
+            // Sending only fails if nobody is subscribed
+            let _ = self.events.send(event);
+        }
+    }
+
+    // The committed frames together with the external frames they can refer to.
+    fn merged_buffer(&self) -> HashMap<String, TransformStamped> {
+        merge_buffers(&self.store.external(), &self.store.snapshot())
+    }
+
+    // Hand the new buffer to the store and record the history samples of the frames that changed.
+    // The caller has to hold the pending updates since it took the snapshot of the old buffer.
+    pub(crate) fn commit(&self, old_buffer: &HashMap<String, TransformStamped>, buffer: HashMap<String, TransformStamped>) {
+        self.commit_to_store(old_buffer, buffer, false)
+    }
+
+    // Joint states are committed like everything else, but the store doesn't have to persist them
+    fn commit_to_store(&self, old_buffer: &HashMap<String, TransformStamped>, buffer: HashMap<String, TransformStamped>, transient: bool) {
+        record_buffer_changes(&mut self.history.lock().unwrap(), old_buffer, &buffer, &self.store.external(), self.cache_time);
+        if let Some(diff) = buffer_diff(old_buffer, &buffer) {
+            follow_joint_frames(&mut self.joints.lock().unwrap(), &diff);
+            if transient {
+                self.store.commit_transient(buffer, &diff);
+            } else {
+                self.store.commit(buffer, &diff);
+            }
+        }
+    }
+
+    fn record_undo(&self, old_buffer: &HashMap<String, TransformStamped>, new_buffer: &HashMap<String, TransformStamped>) {
+        if let Some(diff) = buffer_diff(old_buffer, new_buffer) {
+            push_undo(&mut self.undo_stack.lock().unwrap(), diff, self.undo_depth);
+            self.redo_stack.lock().unwrap().clear();
+        }
+    }
+}
+
+// Our frames take precedence over external frames with the same name.
+fn merge_buffers(
+    external: &HashMap<String, TransformStamped>,
+    buffer: &HashMap<String, TransformStamped>,
+) -> HashMap<String, TransformStamped> {
+    let mut merged = external.clone();
+    merged.extend(buffer.clone());
+    merged
+}
+
+// Apply the pending updates one by one to the working buffer and report the outcome of each.
+// External frames can't be changed, but frames can't be added over them, they can be cloned,
+// and they take part in the cycle checks and the lookups done when reparenting.
+fn apply_updates(
+    buffer: &mut HashMap<String, TransformStamped>,
+    external: &HashMap<String, TransformStamped>,
+    pending_updates: &[UpdateContext],
+) -> ApplyReport {
+    let mut report = ApplyReport::default();
+
+    for update_context in pending_updates.iter() {
+        let name = &update_context.name;
+        let result = match update_context.update_type {
+            UpdateType::Add => {
+                if name != &update_context.transform.child_frame_id {
+                    log::info!("Transform name '{name}' in buffer doesn't match the child_frame_id {}, they should be the same. Not added.", update_context.transform.child_frame_id);
+                    Err(TransformError::NameMismatch {
+                        name: name.to_string(),
+                        child_frame_id: update_context.transform.child_frame_id.clone(),
+                    })
+                } else if buffer.contains_key(name) || external.contains_key(name) {
+                    log::info!("Transform '{}' already exists, not added.", name);
+                    Err(TransformError::AlreadyExists(name.to_string()))
+                } else {
+                    let transform = update_context.transform.clone();
+                    if would_close_cycle(name, &transform.parent_frame_id, buffer, external) {
+                        log::info!("Transform '{}' would produce cycle, not added.", name);
+                        Err(TransformError::CycleDetected(name.to_string()))
                     } else {
-                        let transform = update_context.transform.clone();
-                        if check_would_produce_cycle(&transform, &buffer) {
-                            log::info!("Transform '{}' would produce cycle, not added.", name);
-                        } else {
-                            buffer.insert(name.to_string(), transform);
-                            log::info!("Inserted transform '{name}'.");
-                        }
+                        buffer.insert(name.to_string(), transform);
+                        log::info!("Inserted transform '{name}'.");
+                        Ok(())
                     }
                 }
-                UpdateType::Move => {
-                    if let Some(transform) = buffer.get_mut(name) {
-                        transform.transform = update_context.transform.transform.clone();
-                        log::info!("Moved transform '{name}'.");
-                    } else {
-                        log::info!("Can't move transform '{}' because it doesn't exist.", name);
-                    }
+            }
+            UpdateType::Move => {
+                if let Some(transform) = buffer.get_mut(name) {
+                    transform.transform = update_context.transform.transform;
+                    transform.time_stamp = SystemTime::now();
+                    log::info!("Moved transform '{name}'.");
+                    Ok(())
+                } else {
+                    log::info!("Can't move transform '{}' because it doesn't exist.", name);
+                    Err(TransformError::FrameNotFound(name.to_string()))
                 }
-                UpdateType::Remove => {
-                    if let Some(_) = buffer.get(name) {
-                        buffer.remove(name);
-                        log::info!("Removed transform '{name}'.");
-                    } else {
-                        log::info!(
-                            "Can't remove transform '{}' because it doesn't exist.",
-                            name
-                        );
-                    }
+            }
+            UpdateType::Replace => {
+                let transform = &update_context.transform;
+                if name != &transform.child_frame_id {
+                    log::info!("Transform name '{name}' doesn't match the child_frame_id {}, not replaced.", transform.child_frame_id);
+                    Err(TransformError::NameMismatch {
+                        name: name.to_string(),
+                        child_frame_id: transform.child_frame_id.clone(),
+                    })
+                } else if !buffer.contains_key(name) {
+                    log::info!("Can't replace transform '{}' because it doesn't exist.", name);
+                    Err(TransformError::FrameNotFound(name.to_string()))
+                } else if would_close_cycle(name, &transform.parent_frame_id, buffer, external) {
+                    log::info!("Transform '{}' would produce cycle if replaced, no action taken.", name);
+                    Err(TransformError::CycleDetected(name.to_string()))
+                } else {
+                    let mut transform = transform.clone();
+                    transform.time_stamp = SystemTime::now();
+                    buffer.insert(name.to_string(), transform);
+                    log::info!("Replaced transform '{name}'.");
+                    Ok(())
                 }
-                UpdateType::Rename => {
-                    if let Some(transform) = buffer.clone().get(name) {
-                        let mut temp = transform.clone();
-                        temp.child_frame_id = update_context.transform.child_frame_id.clone();
-                        buffer.remove(name);
-                        buffer.insert(
-                            update_context.transform.child_frame_id.to_string(),
-                            temp.clone(),
-                        );
-                        log::info!("Renamed transform '{name}' to '{}'.", update_context.transform.child_frame_id);
-                    } else {
-                        log::info!(
-                            "Can't rename transform '{}' because it doesn't exist.",
-                            name
-                        );
-                    }
+            }
+            UpdateType::Remove => {
+                if buffer.remove(name).is_some() {
+                    log::info!("Removed transform '{name}'.");
+                    Ok(())
+                } else {
+                    log::info!(
+                        "Can't remove transform '{}' because it doesn't exist.",
+                        name
+                    );
+                    Err(TransformError::FrameNotFound(name.to_string()))
                 }
-                UpdateType::Reparent => {
-                    if let Some(transform) = buffer.get(name) {
-                        let mut temp = transform.clone();
-                        let old_parent = temp.parent_frame_id;
-                        temp.parent_frame_id = update_context.transform.parent_frame_id.clone();
-                        if check_would_produce_cycle(&temp, &buffer) {
-                            log::info!("Transform '{}' would produce cycle if reparented, no action taken.", name);
-                        } else {
-                            let new_transform = Self::lookup_transform(&self, &temp.parent_frame_id, &temp.child_frame_id);
-                            temp.transform = new_transform.unwrap().transform;
-                            buffer.insert(name.clone(), temp);
-                            log::info!("Reparented transform '{name}' from '{}' to '{}'.", old_parent, update_context.transform.parent_frame_id);
-                        }
-                    } else {
-                        log::info!(
-                            "Can't reparent transform '{}' because it doesn't exist.",
-                            name
-                        );
-                    }
+            }
+            UpdateType::Rename => {
+                let rename_to = &update_context.transform.child_frame_id;
+                if buffer.contains_key(rename_to) || external.contains_key(rename_to) {
+                    log::info!("Can't rename transform '{name}' to '{rename_to}' because '{rename_to}' already exists.");
+                    Err(TransformError::AlreadyExists(rename_to.to_string()))
+                } else if let Some(transform) = buffer.remove(name) {
+                    let mut temp = transform;
+                    temp.child_frame_id = rename_to.clone();
+                    buffer.insert(rename_to.to_string(), temp);
+                    log::info!("Renamed transform '{name}' to '{}'.", rename_to);
+                    Ok(())
+                } else {
+                    log::info!(
+                        "Can't rename transform '{}' because it doesn't exist.",
+                        name
+                    );
+                    Err(TransformError::FrameNotFound(name.to_string()))
                 }
-                UpdateType::Clone => {
-                    if let Some(transform) = buffer.get(name) {
-                        let mut new_transform = transform.clone();
-                        new_transform.child_frame_id =
-                            update_context.transform.child_frame_id.clone();
-                        buffer.insert(
-                            update_context.transform.child_frame_id.clone(),
-                            new_transform.clone(),
-                        );
-                        log::info!("Cloned transform '{name}' as '{}'.", new_transform.child_frame_id);
+            }
+            UpdateType::Reparent(mode) => {
+                if let Some(transform) = buffer.get(name) {
+                    let mut temp = transform.clone();
+                    let old_parent = temp.parent_frame_id;
+                    temp.parent_frame_id = update_context.transform.parent_frame_id.clone();
+                    if would_close_cycle(name, &temp.parent_frame_id, buffer, external) {
+                        log::info!("Transform '{}' would produce cycle if reparented, no action taken.", name);
+                        Err(TransformError::CycleDetected(name.to_string()))
                     } else {
-                        log::info!("Can't clone transform '{}' because it doesn't exist.", name);
+                        // The frame still hangs under the old parent, so the lookup gives its pose under the new one
+                        let pose = match mode {
+                            ReparentMode::KeepWorldPose => {
+                                lookup_transform_in_layers(&temp.parent_frame_id, &temp.child_frame_id, &[buffer, external])
+                                    .map(|new_transform| new_transform.transform)
+                            }
+                            ReparentMode::KeepLocalOffset => Ok(temp.transform),
+                        };
+                        match pose {
+                            Ok(pose) => {
+                                temp.transform = pose;
+                                temp.time_stamp = SystemTime::now();
+                                buffer.insert(name.clone(), temp);
+                                log::info!("Reparented transform '{name}' from '{}' to '{}'.", old_parent, update_context.transform.parent_frame_id);
+                                Ok(())
+                            }
+                            Err(e) => {
+                                log::info!("Can't reparent transform '{name}', lookup failed with: '{e}'.");
+                                Err(e)
+                            }
+                        }
                     }
+                } else {
+                    log::info!(
+                        "Can't reparent transform '{}' because it doesn't exist.",
+                        name
+                    );
+                    Err(TransformError::FrameNotFound(name.to_string()))
                 }
-                UpdateType::DeleteAll => {
-                    buffer.clear();
-                    log::info!("All transforms deleted from the buffer.");
+            }
+            UpdateType::Clone => {
+                let clone_name = &update_context.transform.child_frame_id;
+                if buffer.contains_key(clone_name) || external.contains_key(clone_name) {
+                    log::info!("Can't clone transform '{name}' as '{clone_name}' because '{clone_name}' already exists.");
+                    Err(TransformError::AlreadyExists(clone_name.to_string()))
+                } else if let Some(transform) = buffer.get(name).or(external.get(name)) {
+                    let mut new_transform = transform.clone();
+                    new_transform.child_frame_id = clone_name.clone();
+                    buffer.insert(clone_name.clone(), new_transform);
+                    log::info!("Cloned transform '{name}' as '{}'.", clone_name);
+                    Ok(())
+                } else {
+                    log::info!("Can't clone transform '{}' because it doesn't exist.", name);
+                    Err(TransformError::FrameNotFound(name.to_string()))
                 }
             }
+            UpdateType::DeleteAll => {
+                buffer.clear();
+                log::info!("All transforms deleted from the buffer.");
+                Ok(())
+            }
+        };
+        report.updates.push(UpdateReport::new(update_context, result));
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+
+    use nalgebra::Isometry3;
+    use std::collections::HashMap;
+    use crate::*;
+
+    fn frame(parent: &str, child: &str) -> TransformStamped {
+        let mut transform = TransformStamped::default();
+        transform.parent_frame_id = parent.to_string();
+        transform.child_frame_id = child.to_string();
+        transform
+    }
+
+    #[test]
+    fn test_apply_report() {
+        let buffer = SpaceTreeServer::new("test");
+        assert!(buffer.apply_changes().updates.is_empty());
+
+        buffer.insert_transform("a", frame("world", "a")).unwrap();
+        buffer.insert_transform("b", frame("world", "b")).unwrap();
+        let report = buffer.apply_changes();
+        assert_eq!(report.updates.len(), 2);
+        assert!(report.is_success());
+
+        // 'b' already exists, so adding it again is rejected when applied
+        buffer.rename_transform("a", "x").unwrap();
+        buffer.insert_transform("b", frame("world", "b")).unwrap();
+        let report = buffer.apply_changes();
+        assert!(!report.is_success());
+        assert_eq!(report.applied().len(), 1);
+        assert_eq!(report.applied()[0].target, Some("x".to_string()));
+        assert_eq!(report.rejected().len(), 1);
+        assert_eq!(report.errors(), vec![&TransformError::AlreadyExists("b".to_string())]);
+    }
+
+    #[test]
+    fn test_pending_updates_keep_their_order() {
+        let buffer = SpaceTreeServer::new("test");
+        buffer.insert_transform("a", frame("world", "a")).unwrap();
+        buffer.rename_transform("a", "b").unwrap();
+        buffer.move_transform("b", Isometry3::translation(1.0, 0.0, 0.0)).unwrap();
+        buffer.clone_transform("b", "c").unwrap();
+        buffer.reparent_transform("c", "b").unwrap();
+        assert_eq!(buffer.move_transform("a", Isometry3::identity()), Err(TransformError::FrameNotFound("a".to_string())));
+
+        let report = buffer.apply_changes();
+        assert!(report.is_success());
+        let update_types: Vec<UpdateType> = report.updates.iter().map(|update| update.update_type.clone()).collect();
+        assert_eq!(update_types, vec![UpdateType::Add, UpdateType::Rename, UpdateType::Move, UpdateType::Clone, UpdateType::Reparent(ReparentMode::KeepWorldPose)]);
+
+        let c = buffer.lookup_transform("world", "c").unwrap();
+        assert_eq!(c.parent_frame_id, "world");
+        assert_eq!(c.transform.translation.x, 1.0);
+        assert_eq!(buffer.store.snapshot()["c"].parent_frame_id, "b");
+    }
+
+    #[test]
+    fn test_apply_changes_atomic() {
+        let buffer = SpaceTreeServer::new("test");
+        buffer.insert_transform("a", frame("world", "a")).unwrap();
+        buffer.insert_transform("b", frame("world", "b")).unwrap();
+        assert!(buffer.apply_changes_atomic().is_success());
+        assert_eq!(buffer.get_local_transform_names().len(), 2);
+
+        // Adding 'b' again is rejected, so the rename isn't committed either
+        buffer.rename_transform("a", "x").unwrap();
+        buffer.insert_transform("b", frame("world", "b")).unwrap();
+        let report = buffer.apply_changes_atomic();
+        assert!(report.applied().is_empty());
+        assert_eq!(
+            report.errors(),
+            vec![&TransformError::RolledBack, &TransformError::AlreadyExists("b".to_string())]
+        );
+        let mut names = buffer.get_local_transform_names();
+        names.sort();
+        assert_eq!(names, vec!["a", "b"]);
+
+        // Renaming 'c' to 'x' closes the cycle x -> d -> x
+        buffer.insert_transform("c", frame("d", "c")).unwrap();
+        buffer.insert_transform("d", frame("x", "d")).unwrap();
+        assert!(buffer.apply_changes_atomic().is_success());
+        buffer.rename_transform("c", "x").unwrap();
+        let report = buffer.apply_changes_atomic();
+        assert!(matches!(report.errors()[..], [TransformError::CycleDetected(_)]));
+        assert!(buffer.get_local_transform_names().contains(&"c".to_string()));
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let buffer = SpaceTreeServer::new("test").with_undo_depth(2);
+        assert!(!buffer.undo());
+
+        buffer.insert_transform("a", frame("world", "a")).unwrap();
+        buffer.apply_changes();
+        buffer.move_transform("a", Isometry3::translation(1.0, 0.0, 0.0)).unwrap();
+        buffer.apply_changes();
+        buffer.rename_transform("a", "b").unwrap();
+        buffer.apply_changes();
+
+        assert!(buffer.undo());
+        assert_eq!(buffer.get_local_transform_names(), vec!["a"]);
+        assert!(buffer.undo());
+        assert_eq!(buffer.lookup_transform("world", "a").unwrap().transform.translation.x, 0.0);
+
+        // The insert fell off the bottom of the undo stack
+        assert!(!buffer.undo());
+
+        assert!(buffer.redo());
+        assert_eq!(buffer.lookup_transform("world", "a").unwrap().transform.translation.x, 1.0);
+
+        // A new batch invalidates what could have been redone
+        buffer.remove_transform("a").unwrap();
+        buffer.apply_changes();
+        assert!(!buffer.redo());
+        assert!(buffer.undo());
+        assert_eq!(buffer.get_local_transform_names(), vec!["a"]);
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let buffer = SpaceTreeServer::new("test");
+        let mut events = buffer.subscribe();
+
+        buffer.insert_transform("a", frame("world", "a")).unwrap();
+        buffer.insert_transform("a", frame("world", "a")).unwrap();
+        buffer.rename_transform("a", "b").unwrap();
+        buffer.apply_changes();
+        assert_eq!(events.try_recv(), Ok(SpaceTreeEvent::FrameAdded("a".to_string())));
+        assert_eq!(events.try_recv(), Ok(SpaceTreeEvent::FrameRenamed { name: "a".to_string(), rename_to: "b".to_string() }));
+        assert!(events.try_recv().is_err());
+
+        // Undoing restores the frames, so the events describe the end result
+        buffer.undo();
+        assert_eq!(events.try_recv(), Ok(SpaceTreeEvent::FrameRemoved("b".to_string())));
+        buffer.redo();
+        assert_eq!(events.try_recv(), Ok(SpaceTreeEvent::FrameAdded("b".to_string())));
+
+        buffer.delete_all_transforms();
+        buffer.apply_changes();
+        assert_eq!(events.try_recv(), Ok(SpaceTreeEvent::AllDeleted));
+    }
+
+    #[test]
+    fn test_set_joint_positions() {
+        let path = std::env::temp_dir().join(format!("r2r_transforms_joints_{}.urdf", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"<robot name="slider">
+                <link name="rail"/>
+                <link name="carriage"/>
+                <joint name="slide" type="prismatic">
+                    <parent link="rail"/>
+                    <child link="carriage"/>
+                    <axis xyz="1 0 0"/>
+                </joint>
+            </robot>"#,
+        )
+        .unwrap();
+
+        let buffer = SpaceTreeServer::new("test");
+        buffer.load_urdf(path.to_str().unwrap(), Some("world")).unwrap();
+
+        // The joints can be moved before the loaded frames are applied
+        let positions = HashMap::from([("slide".to_string(), 0.3), ("unknown".to_string(), 1.0)]);
+        assert_eq!(
+            buffer.set_joint_positions(&positions),
+            Err(vec![TransformError::FrameNotFound("unknown".to_string())])
+        );
+        assert!(buffer.apply_changes().is_success());
+        let carriage = buffer.lookup_transform("world", "carriage").unwrap();
+        assert!((carriage.transform.translation.x - 0.3).abs() < 1e-9);
+
+        // A second robot with the same joint name needs the scope
+        std::fs::write(
+            &path,
+            r#"<robot name="slider">
+                <link name="rail_2"/>
+                <link name="carriage_2"/>
+                <joint name="slide" type="prismatic">
+                    <parent link="rail_2"/>
+                    <child link="carriage_2"/>
+                    <axis xyz="0 1 0"/>
+                </joint>
+            </robot>"#,
+        )
+        .unwrap();
+        buffer.load_urdf(path.to_str().unwrap(), Some("table")).unwrap();
+        buffer.apply_changes();
+        let positions = HashMap::from([("slide".to_string(), 0.5)]);
+        assert_eq!(
+            buffer.set_joint_positions(&positions),
+            Err(vec![TransformError::AmbiguousJoint {
+                joint: "slide".to_string(),
+                scopes: vec!["world".to_string(), "table".to_string()],
+            }])
+        );
+        buffer.set_joint_positions_in("table", &positions).unwrap();
+        buffer.apply_changes();
+        let carriage_2 = buffer.lookup_transform("table", "carriage_2").unwrap();
+        assert!((carriage_2.transform.translation.y - 0.5).abs() < 1e-9);
+        let carriage = buffer.lookup_transform("world", "carriage").unwrap();
+        assert!((carriage.transform.translation.x - 0.3).abs() < 1e-9);
+
+        // The joint follows its frame when it is renamed, and is gone once the frame is removed,
+        // so it doesn't move a new frame that takes over the old name
+        buffer.rename_transform("carriage_2", "shuttle").unwrap();
+        buffer.apply_changes();
+        buffer.set_joint_positions_in("table", &HashMap::from([("slide".to_string(), 0.7)])).unwrap();
+        buffer.apply_changes();
+        let shuttle = buffer.lookup_transform("table", "shuttle").unwrap();
+        assert!((shuttle.transform.translation.y - 0.7).abs() < 1e-9);
+        buffer.remove_transform("shuttle").unwrap();
+        buffer.insert_transform("shuttle", frame("table", "shuttle")).unwrap();
+        buffer.apply_changes();
+        assert_eq!(
+            buffer.commit_joint_positions(Some("table"), &positions),
+            Err(vec![TransformError::FrameNotFound("slide".to_string())])
+        );
+        assert_eq!(buffer.lookup_transform("table", "shuttle").unwrap().transform.translation.y, 0.0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_commit_joint_positions() {
+        let path = std::env::temp_dir().join(format!("r2r_transforms_joint_commit_{}.urdf", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"<robot name="slider">
+                <link name="rail"/>
+                <link name="carriage"/>
+                <joint name="slide" type="prismatic">
+                    <parent link="rail"/>
+                    <child link="carriage"/>
+                    <axis xyz="1 0 0"/>
+                </joint>
+            </robot>"#,
+        )
+        .unwrap();
+
+        let buffer = SpaceTreeServer::new("test");
+        buffer.load_urdf(path.to_str().unwrap(), Some("world")).unwrap();
+        buffer.apply_changes();
+        let undo_depth = buffer.undo_stack.lock().unwrap().len();
+        buffer.insert_transform("tool", frame("world", "tool")).unwrap();
+        let mut events = buffer.subscribe();
+
+        let positions = HashMap::from([("slide".to_string(), 0.3), ("gripper".to_string(), 1.0)]);
+        assert_eq!(
+            buffer.commit_joint_positions(None, &positions),
+            Err(vec![TransformError::FrameNotFound("gripper".to_string())])
+        );
+        let carriage = buffer.lookup_transform("world", "carriage").unwrap();
+        assert!((carriage.transform.translation.x - 0.3).abs() < 1e-9);
+
+        // The queued frame is still pending, and the joint motion isn't undoable and sends no events
+        assert!(buffer.lookup_transform("world", "tool").is_err());
+        assert_eq!(buffer.undo_stack.lock().unwrap().len(), undo_depth);
+        assert!(events.try_recv().is_err());
+        buffer.apply_changes();
+        assert!(buffer.lookup_transform("world", "tool").is_ok());
+
+        // Joint states that are committed while the user's edits are applied and undone aren't lost
+        let joint_server = buffer.clone();
+        let joint_states = std::thread::spawn(move || {
+            for step in 1..=200 {
+                let positions = HashMap::from([("slide".to_string(), step as f64 * 0.01)]);
+                joint_server.commit_joint_positions(None, &positions).unwrap();
+            }
+        });
+        for step in 0..200 {
+            buffer.move_transform("tool", Isometry3::translation(step as f64, 0.0, 0.0)).unwrap();
+            buffer.apply_changes();
+            if step % 2 == 0 {
+                buffer.undo();
+            } else {
+                buffer.apply_changes_atomic();
+            }
         }
+        joint_states.join().unwrap();
+        let carriage = buffer.lookup_transform("world", "carriage").unwrap();
+        assert!((carriage.transform.translation.x - 2.0).abs() < 1e-9);
 
-        pending_updates.clear();
-        *self.local_buffer.lock().unwrap() = buffer;
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Frames owned by someone else, like the ones that come from /tf in the ROS server
+    #[derive(Clone, Default)]
+    struct ExternalStore {
+        local: MemoryStore,
+        external: HashMap<String, TransformStamped>,
+    }
+
+    impl TransformStore for ExternalStore {
+        fn snapshot(&self) -> HashMap<String, TransformStamped> {
+            self.local.snapshot()
+        }
+
+        fn commit(&self, buffer: HashMap<String, TransformStamped>, diff: &BufferDiff) {
+            self.local.commit(buffer, diff)
+        }
+
+        fn external(&self) -> HashMap<String, TransformStamped> {
+            self.external.clone()
+        }
+    }
+
+    #[test]
+    fn test_external_frames() {
+        let mut base = frame("world", "base");
+        base.transform = Isometry3::translation(1.0, 0.0, 0.0);
+        let store = ExternalStore {
+            external: HashMap::from([("base".to_string(), base)]),
+            ..Default::default()
+        };
+        let buffer = SpaceTreeServer::with_store("test", store);
+
+        let mut a = frame("base", "a");
+        a.transform = Isometry3::translation(0.0, 2.0, 0.0);
+        buffer.insert_transform("a", a).unwrap();
+        buffer.insert_transform("base", frame("world", "base")).unwrap();
+        let report = buffer.apply_changes();
+        assert_eq!(report.errors(), vec![&TransformError::AlreadyExists("base".to_string())]);
+
+        // External frames are part of the lookups, but they can't be renamed over or moved
+        let a_in_world = buffer.lookup_transform("world", "a").unwrap();
+        assert!((a_in_world.transform.translation.vector - nalgebra::Vector3::new(1.0, 2.0, 0.0)).norm() < 1e-9);
+        assert_eq!(buffer.rename_transform("a", "base"), Err(TransformError::AlreadyExists("base".to_string())));
+        assert_eq!(buffer.move_transform("base", Isometry3::identity()), Err(TransformError::FrameNotFound("base".to_string())));
+
+        // They can be cloned, and reparenting away from them keeps the world pose
+        buffer.clone_transform("base", "base_copy").unwrap();
+        buffer.reparent_transform("a", "world").unwrap();
+        assert!(buffer.apply_changes().is_success());
+        let local = buffer.store.snapshot();
+        assert_eq!(local["base_copy"].parent_frame_id, "world");
+        assert!((local["a"].transform.translation.vector - nalgebra::Vector3::new(1.0, 2.0, 0.0)).norm() < 1e-9);
+        assert!(!local.contains_key("base"));
+    }
+
+    #[test]
+    fn test_reparent_modes() {
+        // The new parent comes from the external frames, like a frame published on /tf
+        let mut base = frame("world", "base");
+        base.transform = Isometry3::translation(1.0, 0.0, 0.0);
+        let store = ExternalStore {
+            external: HashMap::from([("base".to_string(), base)]),
+            ..Default::default()
+        };
+        let buffer = SpaceTreeServer::with_store("test", store);
+        for name in ["a", "b"] {
+            let mut transform = frame("world", name);
+            transform.transform = Isometry3::translation(0.0, 2.0, 0.0);
+            buffer.insert_transform(name, transform).unwrap();
+        }
+        buffer.apply_changes();
+
+        buffer.reparent_transform("a", "base").unwrap();
+        buffer.reparent_transform_with_mode("b", "base", ReparentMode::KeepLocalOffset).unwrap();
+        assert!(buffer.apply_changes().is_success());
+
+        let world_a = buffer.lookup_transform("world", "a").unwrap();
+        assert!((world_a.transform.translation.vector - nalgebra::Vector3::new(0.0, 2.0, 0.0)).norm() < 1e-9);
+        let world_b = buffer.lookup_transform("world", "b").unwrap();
+        assert!((world_b.transform.translation.vector - nalgebra::Vector3::new(1.0, 2.0, 0.0)).norm() < 1e-9);
+        let local = buffer.store.snapshot();
+        assert_eq!(local["a"].parent_frame_id, "base");
+        assert!((local["a"].transform.translation.vector - nalgebra::Vector3::new(-1.0, 2.0, 0.0)).norm() < 1e-9);
+    }
+
+    #[test]
+    fn test_lookup_in_forest() {
+        let buffer = SpaceTreeServer::new("test");
+        let mut a = frame("world", "a");
+        a.transform = Isometry3::translation(1.0, 0.0, 0.0);
+        let mut b = frame("a", "b");
+        b.transform = Isometry3::translation(0.0, 1.0, 0.0);
+        let mut tool = frame("cell", "tool");
+        tool.transform = Isometry3::translation(0.0, 0.0, 3.0);
+        for transform in [a, b, tool] {
+            buffer.insert_transform(&transform.child_frame_id.clone(), transform).unwrap();
+        }
+        buffer.apply_changes();
+
+        assert_eq!(buffer.get_tree_roots(), vec!["cell", "world"]);
+        assert_eq!(buffer.get_frame_root("b"), Some("world".to_string()));
+        assert_eq!(buffer.get_frame_root("tool"), Some("cell".to_string()));
+
+        let world_b = buffer.lookup_transform("world", "b").unwrap();
+        assert!((world_b.transform.translation.vector - nalgebra::Vector3::new(1.0, 1.0, 0.0)).norm() < 1e-9);
+        let cell_tool = buffer.lookup_transform("cell", "tool").unwrap();
+        assert!((cell_tool.transform.translation.vector - nalgebra::Vector3::new(0.0, 0.0, 3.0)).norm() < 1e-9);
+        assert!(matches!(
+            buffer.lookup_transform("b", "tool"),
+            Err(TransformError::Disconnected { .. })
+        ));
+        assert!(matches!(
+            buffer.lookup_transform("world", "missing"),
+            Err(TransformError::FrameNotFound(_))
+        ));
+
+        // The world pose can't be kept when reparenting into the other tree, there is no path between
+        // the two roots, so only keeping the local offset under the new parent works
+        buffer.reparent_transform("tool", "b").unwrap();
+        assert!(!buffer.apply_changes().is_success());
+        buffer.reparent_transform_with_mode("tool", "b", ReparentMode::KeepLocalOffset).unwrap();
+        assert!(buffer.apply_changes().is_success());
+        assert_eq!(buffer.get_tree_roots(), vec!["world"]);
+    }
+
+    #[test]
+    fn test_cached_world_poses() {
+        let buffer = SpaceTreeServer::new("test");
+        for (parent, child, x) in [("world", "a", 1.0), ("a", "b", 2.0), ("b", "c", 3.0), ("world", "d", 4.0)] {
+            let mut transform = frame(parent, child);
+            transform.transform = Isometry3::translation(x, 0.0, 0.0);
+            buffer.insert_transform(child, transform).unwrap();
+        }
+        buffer.apply_changes();
+
+        // The cached lookups have to agree with walking the tree
+        let check = |expected: &[(&str, f64)]| {
+            for (name, x) in expected {
+                let cached = buffer.lookup_transform("world", name).unwrap();
+                let walked = lookup_transform_in_tree("world", name, &buffer.store.snapshot()).unwrap();
+                assert_eq!(cached.transform.translation.vector.x, *x);
+                assert!((cached.transform.to_homogeneous() - walked.transform.to_homogeneous()).norm() < 1e-9);
+            }
+        };
+        check(&[("a", 1.0), ("b", 3.0), ("c", 6.0), ("d", 4.0)]);
+        assert_eq!(buffer.store.poses.lock().unwrap().len(), 4);
+
+        // Moving b only drops the poses of b and c
+        buffer.move_transform("b", Isometry3::translation(5.0, 0.0, 0.0)).unwrap();
+        buffer.apply_changes();
+        let cached = buffer.store.poses.lock().unwrap().keys().cloned().collect::<std::collections::HashSet<String>>();
+        assert_eq!(cached, ["a", "d"].iter().map(|name| name.to_string()).collect());
+        check(&[("b", 6.0), ("c", 9.0)]);
+
+        buffer.reparent_transform_with_mode("b", "d", ReparentMode::KeepLocalOffset).unwrap();
+        buffer.apply_changes();
+        check(&[("b", 9.0), ("c", 12.0), ("a", 1.0)]);
+
+        buffer.remove_transform("d").unwrap();
+        buffer.apply_changes();
+        assert_eq!(buffer.lookup_transform("d", "c").unwrap().transform.translation.vector.x, 8.0);
+        assert!(matches!(buffer.lookup_transform("world", "c"), Err(TransformError::Disconnected { .. })));
+
+        buffer.undo();
+        check(&[("c", 12.0)]);
     }
 }
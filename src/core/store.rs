@@ -0,0 +1,272 @@
+use crate::*;
+use nalgebra::Isometry3;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Where a space tree server keeps its committed frames.
+/// The server validates and applies the updates, the store only has to hold the result.
+pub trait TransformStore: Clone + Send + Sync + 'static {
+    /// Returns a copy of all committed frames.
+    fn snapshot(&self) -> HashMap<String, TransformStamped>;
+
+    /// Returns a committed frame.
+    fn get(&self, name: &str) -> Option<TransformStamped> {
+        self.snapshot().get(name).cloned()
+    }
+
+    /// Replaces the committed frames. The diff holds the frames that changed, so that
+    /// stores that persist the frames elsewhere only have to write those.
+    fn commit(&self, buffer: HashMap<String, TransformStamped>, diff: &BufferDiff);
+
+    /// Commits frames that only moved with the joint states. They change at the rate of the joint
+    /// states and come back with the next one, so stores that persist the frames don't have to write them.
+    fn commit_transient(&self, buffer: HashMap<String, TransformStamped>, diff: &BufferDiff) {
+        self.commit(buffer, diff)
+    }
+
+    /// Returns the children of every parent of a committed frame. Stores that keep
+    /// the frames in memory maintain it on every commit, others build it from the snapshot.
+    fn children(&self) -> ChildrenIndex {
+        build_children_index(&self.snapshot())
+    }
+
+    /// Returns the root of the frame's tree among the committed frames, which is the first
+    /// ancestor that isn't committed, and the pose of the frame in it. Stores that keep the
+    /// frames in memory cache the poses and drop them for the frames below a commit's changes.
+    fn root_pose(&self, name: &str) -> Result<(String, Isometry3<f64>), TransformError> {
+        cached_root_pose(name, &self.snapshot(), &mut PoseCache::new())
+    }
+
+    /// Returns the pose of the child in the parent frame and whether the child is active, if both
+    /// are in the same tree of committed frames. Both poses are resolved from the same frames,
+    /// so a commit in between can't leave the lookup with one pose from before it and one after.
+    fn relative_pose(&self, parent: &str, child: &str) -> Option<(Isometry3<f64>, bool)> {
+        relative_root_pose(parent, child, &self.snapshot(), &mut PoseCache::new())
+    }
+
+    /// Returns the frames that are owned by someone else, for example other ROS nodes.
+    /// The committed frames can refer to them and they are used in lookups, but they are never changed.
+    fn external(&self) -> HashMap<String, TransformStamped> {
+        HashMap::new()
+    }
+}
+
+/// Keeps the frames in memory, shared between all clones of the server.
+/// The children index and the pose cache follow the commits, so the buffer shouldn't be changed past the store.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    pub buffer: Arc<Mutex<HashMap<String, TransformStamped>>>,
+    pub children: Arc<Mutex<ChildrenIndex>>,
+    pub poses: Arc<Mutex<PoseCache>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wraps an existing buffer, for example one that is also read by the tf broadcasters.
+    pub fn from_buffer(buffer: &Arc<Mutex<HashMap<String, TransformStamped>>>) -> Self {
+        let children = build_children_index(&buffer.lock().unwrap());
+        Self {
+            buffer: buffer.clone(),
+            children: Arc::new(Mutex::new(children)),
+            poses: Arc::new(Mutex::new(PoseCache::new())),
+        }
+    }
+}
+
+impl TransformStore for MemoryStore {
+    fn snapshot(&self) -> HashMap<String, TransformStamped> {
+        self.buffer.lock().unwrap().clone()
+    }
+
+    fn get(&self, name: &str) -> Option<TransformStamped> {
+        self.buffer.lock().unwrap().get(name).cloned()
+    }
+
+    // The index is updated from the frames that are replaced, not the ones in the diff,
+    // so it stays in step with the buffer whatever the diff was made from
+    fn commit(&self, buffer: HashMap<String, TransformStamped>, diff: &BufferDiff) {
+        let mut old_buffer = self.buffer.lock().unwrap();
+        let mut children = self.children.lock().unwrap();
+        let mut poses = self.poses.lock().unwrap();
+        for name in diff.after.keys() {
+            invalidate_subtree(&mut poses, name, &children);
+            update_children_index(&mut children, name, old_buffer.get(name), buffer.get(name));
+        }
+        *old_buffer = buffer;
+    }
+
+    fn children(&self) -> ChildrenIndex {
+        self.children.lock().unwrap().clone()
+    }
+
+    fn root_pose(&self, name: &str) -> Result<(String, Isometry3<f64>), TransformError> {
+        let buffer = self.buffer.lock().unwrap();
+        cached_root_pose(name, &buffer, &mut self.poses.lock().unwrap())
+    }
+
+    fn relative_pose(&self, parent: &str, child: &str) -> Option<(Isometry3<f64>, bool)> {
+        let buffer = self.buffer.lock().unwrap();
+        relative_root_pose(parent, child, &buffer, &mut self.poses.lock().unwrap())
+    }
+}
+
+/// Keeps the frames in memory and writes every commit into a scenario directory,
+/// or into a single scenario file if the path ends with .json, .yaml or .yml.
+/// The joint states are not written, so a joint frame keeps its file until something else
+/// changes it or the scenario is saved. Until then it is at the pose it had when it was written.
+#[derive(Clone)]
+pub struct FileStore {
+    pub path: String,
+    pub rotation_encoding: RotationEncoding,
+    memory: MemoryStore,
+}
+
+impl FileStore {
+    /// Opens the scenario at the path, which doesn't have to exist yet.
+    pub fn open(path: &str) -> Result<Self, TransformError> {
+        let frames = if std::path::Path::new(path).exists() {
+            load_scenario_from_path(path)?
+        } else {
+            HashMap::new()
+        };
+        Ok(Self {
+            path: path.to_string(),
+            rotation_encoding: RotationEncoding::default(),
+            memory: MemoryStore::from_buffer(&Arc::new(Mutex::new(frames))),
+        })
+    }
+
+    /// Sets the rotation encoding of the written frames.
+    pub fn with_rotation_encoding(mut self, rotation_encoding: RotationEncoding) -> Self {
+        self.rotation_encoding = rotation_encoding;
+        self
+    }
+}
+
+impl TransformStore for FileStore {
+    fn snapshot(&self) -> HashMap<String, TransformStamped> {
+        self.memory.snapshot()
+    }
+
+    fn get(&self, name: &str) -> Option<TransformStamped> {
+        self.memory.get(name)
+    }
+
+    // The frames stay committed in memory even if writing them fails
+    fn commit(&self, buffer: HashMap<String, TransformStamped>, diff: &BufferDiff) {
+        let result = if is_scenario_file(&self.path) {
+            save_scenario_file(&self.path, &buffer, self.rotation_encoding)
+        } else {
+            let changed: Vec<&TransformStamped> = diff.after.values().flatten().collect();
+            let removed: Vec<&str> = diff
+                .after
+                .iter()
+                .filter(|(_, frame)| frame.is_none())
+                .map(|(name, _)| name.as_str())
+                .collect();
+            save_frames(&self.path, &changed, self.rotation_encoding)
+                .and_then(|_| remove_frame_files(&self.path, &removed))
+        };
+        if let Err(e) = result {
+            log::error!(target: "r2r_transforms", "Writing the frames to '{}' failed with: '{}'.", self.path, e);
+        }
+        self.memory.commit(buffer, diff);
+    }
+
+    fn commit_transient(&self, buffer: HashMap<String, TransformStamped>, diff: &BufferDiff) {
+        self.memory.commit(buffer, diff);
+    }
+
+    fn children(&self) -> ChildrenIndex {
+        self.memory.children()
+    }
+
+    fn root_pose(&self, name: &str) -> Result<(String, Isometry3<f64>), TransformError> {
+        self.memory.root_pose(name)
+    }
+
+    fn relative_pose(&self, parent: &str, child: &str) -> Option<(Isometry3<f64>, bool)> {
+        self.memory.relative_pose(parent, child)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_file_store_writes_commits() {
+        let path = std::env::temp_dir()
+            .join(format!("r2r_transforms_store_{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let _ = fs::remove_dir_all(&path);
+
+        let server = SpaceTreeServer::with_store("test", FileStore::open(&path).unwrap());
+        let mut a = TransformStamped::default();
+        a.parent_frame_id = "world".to_string();
+        a.child_frame_id = "a".to_string();
+        server.insert_transform("a", a.clone()).unwrap();
+        let mut b = a.clone();
+        b.child_frame_id = "b".to_string();
+        server.insert_transform("b", b).unwrap();
+        server.apply_changes();
+
+        let mut files = list_frames_in_dir(&path).unwrap();
+        files.sort();
+        assert_eq!(files, vec![format!("{}/a.json", path), format!("{}/b.json", path)]);
+
+        server.remove_transform("b").unwrap();
+        server.apply_changes();
+        assert_eq!(list_frames_in_dir(&path).unwrap(), vec![format!("{}/a.json", path)]);
+
+        // A new store starts from what was written
+        let reopened = FileStore::open(&path).unwrap();
+        assert_eq!(reopened.snapshot().keys().collect::<Vec<&String>>(), vec!["a"]);
+
+        server.undo();
+        assert!(FileStore::open(&path).unwrap().get("b").is_some());
+
+        // Joint states are only committed in memory
+        let old_buffer = server.store.snapshot();
+        let mut buffer = old_buffer.clone();
+        buffer.get_mut("a").unwrap().transform.translation.x = 1.0;
+        let diff = buffer_diff(&old_buffer, &buffer).unwrap();
+        server.store.commit_transient(buffer, &diff);
+        assert_eq!(server.store.get("a").unwrap().transform.translation.x, 1.0);
+        assert_eq!(FileStore::open(&path).unwrap().get("a").unwrap().transform.translation.x, 0.0);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_memory_store_children_index() {
+        let server = SpaceTreeServer::new("test");
+        for (child, parent) in [("a", "world"), ("b", "a"), ("c", "a")] {
+            let mut transform = TransformStamped::default();
+            transform.parent_frame_id = parent.to_string();
+            transform.child_frame_id = child.to_string();
+            server.insert_transform(child, transform).unwrap();
+        }
+        server.apply_changes();
+        assert_eq!(server.store.children()["a"], vec!["b", "c"]);
+
+        server.reparent_transform("c", "world").unwrap();
+        server.remove_transform("b").unwrap();
+        server.apply_changes();
+        assert_eq!(server.store.children(), build_children_index(&server.store.snapshot()));
+        assert_eq!(server.store.children()["world"], vec!["a", "c"]);
+        assert!(!server.store.children().contains_key("a"));
+
+        server.undo();
+        assert_eq!(server.store.children(), build_children_index(&server.store.snapshot()));
+        assert_eq!(server.store.children()["a"], vec!["b", "c"]);
+    }
+}
@@ -1,29 +1,71 @@
 
-use nalgebra::{Isometry3, Quaternion, UnitQuaternion, Vector3};
+use crate::{build_children_index, find_cyclic_frame, TransformError};
+use nalgebra::{Isometry3, Matrix3, Quaternion, Unit, UnitQuaternion, Vector3};
+#[cfg(feature = "ros")]
+use r2r::builtin_interfaces::msg::Time;
+#[cfg(feature = "ros")]
 use r2r::geometry_msgs::msg::Transform;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
+use std::collections::{HashMap, HashSet};
 // use serde::Deserialize;
 // use structopt::StructOpt;
-use tokio::time::Instant;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct JsonTranslation {
     pub x: f64,
     pub y: f64,
     pub z: f64,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
-pub struct JsonRotation {
-    pub x: f64,
-    pub y: f64,
-    pub z: f64,
-    pub w: f64,
+// A rotation can be given as a quaternion, roll-pitch-yaw, axis-angle or a rotation matrix.
+// Angles are in radians unless degrees is set to true.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum JsonRotation {
+    Quaternion {
+        x: f64,
+        y: f64,
+        z: f64,
+        w: f64,
+    },
+    Rpy {
+        roll: f64,
+        pitch: f64,
+        yaw: f64,
+        #[serde(default, skip_serializing_if = "is_false")]
+        degrees: bool,
+    },
+    AxisAngle {
+        axis: [f64; 3],
+        angle: f64,
+        #[serde(default, skip_serializing_if = "is_false")]
+        degrees: bool,
+    },
+    // Row-major
+    Matrix {
+        matrix: [[f64; 3]; 3],
+    },
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// The rotation encoding used when frames are written to files.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RotationEncoding {
+    #[default]
+    Quaternion,
+    Rpy,
+    RpyDegrees,
+    AxisAngle,
+    Matrix,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct JsonTransform {
     pub translation: JsonTranslation,
     pub rotation: JsonRotation,
@@ -37,7 +79,7 @@ impl JsonTransform {
                 y: 0.0,
                 z: 0.0
             },
-            rotation: JsonRotation {
+            rotation: JsonRotation::Quaternion {
                 x: 0.0,
                 y: 0.0,
                 z: 0.0,
@@ -47,18 +89,127 @@ impl JsonTransform {
     }
 }
 
-pub fn json_transform_to_isometry(json: JsonTransform) -> Isometry3<f64> {
+// Rotations that can't be turned into one are rejected instead of being read as something else:
+// a zero or non-finite quaternion or axis, and a matrix that isn't a rotation, like a reflection.
+// Small deviations are fine, quaternions are normalized and matrices are projected onto a rotation.
+pub fn json_rotation_to_unit_quaternion(rotation: &JsonRotation) -> Result<UnitQuaternion<f64>, String> {
+    let to_radians = |angle: f64, degrees: bool| if degrees { angle.to_radians() } else { angle };
+    match rotation {
+        JsonRotation::Quaternion { x, y, z, w } => {
+            UnitQuaternion::try_new(Quaternion::new(*w, *x, *y, *z), 1e-12)
+                .filter(|rotation| rotation.coords.iter().all(|q| q.is_finite()))
+                .ok_or_else(|| "The quaternion should be finite and not zero.".to_string())
+        }
+        JsonRotation::Rpy { roll, pitch, yaw, degrees } => Ok(UnitQuaternion::from_euler_angles(
+            to_radians(*roll, *degrees),
+            to_radians(*pitch, *degrees),
+            to_radians(*yaw, *degrees),
+        )),
+        JsonRotation::AxisAngle { axis, angle, degrees } => {
+            match Unit::try_new(Vector3::new(axis[0], axis[1], axis[2]), 1e-12) {
+                Some(axis) => Ok(UnitQuaternion::from_axis_angle(&axis, to_radians(*angle, *degrees))),
+                None => Err("The rotation axis should not be zero.".to_string()),
+            }
+        }
+        JsonRotation::Matrix { matrix } => {
+            let matrix = Matrix3::from_fn(|row, column| matrix[row][column]);
+            let orthonormal = (matrix * matrix.transpose() - Matrix3::identity()).norm() < 1e-3;
+            if orthonormal && (matrix.determinant() - 1.0).abs() < 1e-3 {
+                Ok(UnitQuaternion::from_matrix(&matrix))
+            } else {
+                Err("The rotation matrix should be orthonormal with determinant 1.".to_string())
+            }
+        }
+    }
+}
+
+pub fn unit_quaternion_to_json_rotation(rotation: &UnitQuaternion<f64>, encoding: RotationEncoding) -> JsonRotation {
+    match encoding {
+        RotationEncoding::Quaternion => JsonRotation::Quaternion {
+            x: rotation.i,
+            y: rotation.j,
+            z: rotation.k,
+            w: rotation.w,
+        },
+        RotationEncoding::Rpy | RotationEncoding::RpyDegrees => {
+            let degrees = encoding == RotationEncoding::RpyDegrees;
+            let (roll, pitch, yaw) = rotation.euler_angles();
+            let from_radians = |angle: f64| if degrees { angle.to_degrees() } else { angle };
+            JsonRotation::Rpy {
+                roll: from_radians(roll),
+                pitch: from_radians(pitch),
+                yaw: from_radians(yaw),
+                degrees,
+            }
+        }
+        RotationEncoding::AxisAngle => match rotation.axis_angle() {
+            Some((axis, angle)) => JsonRotation::AxisAngle {
+                axis: [axis.x, axis.y, axis.z],
+                angle,
+                degrees: false,
+            },
+            None => JsonRotation::AxisAngle {
+                axis: [0.0, 0.0, 1.0],
+                angle: 0.0,
+                degrees: false,
+            },
+        },
+        RotationEncoding::Matrix => {
+            let matrix = rotation.to_rotation_matrix();
+            JsonRotation::Matrix {
+                matrix: [0, 1, 2].map(|row| [0, 1, 2].map(|column| matrix[(row, column)])),
+            }
+        }
+    }
+}
+
+pub fn json_transform_to_isometry(json: JsonTransform) -> Result<Isometry3<f64>, String> {
     let translation = Vector3::new(json.translation.x, json.translation.y, json.translation.z);
-    let rotation = UnitQuaternion::from_quaternion(Quaternion::new(
-        json.rotation.w,
-        json.rotation.x,
-        json.rotation.y,
-        json.rotation.z,
-    ));
+    let rotation = json_rotation_to_unit_quaternion(&json.rotation)?;
 
-    Isometry3::from_parts(translation.into(), rotation)
+    Ok(Isometry3::from_parts(translation.into(), rotation))
+}
+
+pub fn isometry_to_json_transform(isometry: &Isometry3<f64>, encoding: RotationEncoding) -> JsonTransform {
+    JsonTransform {
+        translation: JsonTranslation {
+            x: isometry.translation.x,
+            y: isometry.translation.y,
+            z: isometry.translation.z,
+        },
+        rotation: unit_quaternion_to_json_rotation(&isometry.rotation, encoding),
+    }
 }
 
+// The per-frame file format of a scenario directory
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct JsonFrame {
+    pub child_frame_id: String,
+    pub parent_frame_id: String,
+    pub transform: JsonTransform,
+    pub metadata: Value,
+}
+
+impl JsonFrame {
+    // The active flag lives in the metadata, so it is written back there
+    pub fn from_transform_stamped(frame: &TransformStamped, encoding: RotationEncoding) -> JsonFrame {
+        let mut metadata = frame.metadata.clone();
+        if metadata.is_null() {
+            metadata = Value::Object(serde_json::Map::new());
+        }
+        if let Some(object) = metadata.as_object_mut() {
+            object.insert("active_transform".to_string(), Value::Bool(frame.active));
+        }
+        JsonFrame {
+            child_frame_id: frame.child_frame_id.clone(),
+            parent_frame_id: frame.parent_frame_id.clone(),
+            transform: isometry_to_json_transform(&frame.transform, encoding),
+            metadata,
+        }
+    }
+}
+
+#[cfg(feature = "ros")]
 pub fn ros_transform_to_isometry(t: Transform) -> Isometry3<f64> {
     let translation = Vector3::new(t.translation.x, t.translation.y, t.translation.z);
     let rotation = UnitQuaternion::from_quaternion(Quaternion::new(
@@ -71,15 +222,77 @@ pub fn ros_transform_to_isometry(t: Transform) -> Isometry3<f64> {
     Isometry3::from_parts(translation.into(), rotation)
 }
 
-// Isometry3 should be similar to the Transform message in ROS
-#[derive(Debug, Clone, PartialEq)]
+// The stamp of a ROS message header. A zero stamp asks tf for the latest transform, so it is taken as now.
+#[cfg(feature = "ros")]
+pub fn ros_time_to_system_time(stamp: &Time) -> SystemTime {
+    if stamp.sec == 0 && stamp.nanosec == 0 {
+        return SystemTime::now();
+    }
+    json_time_to_system_time(&JsonTime {
+        sec: stamp.sec as i64,
+        nanosec: stamp.nanosec,
+    })
+}
+
+// Wall-clock time like the ROS Time message, seconds and nanoseconds since the UNIX epoch
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct JsonTime {
+    pub sec: i64,
+    pub nanosec: u32,
+}
+
+pub fn system_time_to_json_time(time: &SystemTime) -> JsonTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => JsonTime {
+            sec: since.as_secs() as i64,
+            nanosec: since.subsec_nanos(),
+        },
+        // Before the epoch, the nanoseconds still count forward from sec like in ROS
+        Err(e) => {
+            let before = e.duration();
+            let sec = -(before.as_secs() as i64) - if before.subsec_nanos() > 0 { 1 } else { 0 };
+            let nanosec = if before.subsec_nanos() > 0 { 1_000_000_000 - before.subsec_nanos() } else { 0 };
+            JsonTime { sec, nanosec }
+        }
+    }
+}
+
+pub fn json_time_to_system_time(time: &JsonTime) -> SystemTime {
+    let nanosec = Duration::from_nanos(time.nanosec as u64);
+    if time.sec >= 0 {
+        UNIX_EPOCH + Duration::from_secs(time.sec as u64) + nanosec
+    } else {
+        UNIX_EPOCH - Duration::from_secs(time.sec.unsigned_abs()) + nanosec
+    }
+}
+
+fn serialize_system_time<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    system_time_to_json_time(time).serialize(serializer)
+}
+
+fn deserialize_system_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+    JsonTime::deserialize(deserializer).map(|time| json_time_to_system_time(&time))
+}
+
+fn serialize_isometry_3_f64<S: Serializer>(isometry: &Isometry3<f64>, serializer: S) -> Result<S::Ok, S::Error> {
+    isometry_to_json_transform(isometry, RotationEncoding::Quaternion).serialize(serializer)
+}
+
+fn deserialize_isometry_3_f64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Isometry3<f64>, D::Error> {
+    JsonTransform::deserialize(deserializer)
+        .and_then(|transform| json_transform_to_isometry(transform).map_err(serde::de::Error::custom))
+}
+
+// Isometry3 should be similar to the Transform message in ROS.
+// On the wire, the time stamp is a JsonTime and the transform has the frame file format.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct TransformStamped {
     pub active: bool,
-    // #[serde(deserialize_with = "deserialize_instant")]
-    pub time_stamp: Instant,
+    #[serde(serialize_with = "serialize_system_time", deserialize_with = "deserialize_system_time")]
+    pub time_stamp: SystemTime,
     pub parent_frame_id: String,
     pub child_frame_id: String,
-    // #[serde(deserialize_with = "deserialize_isometry_3_f64")]
+    #[serde(serialize_with = "serialize_isometry_3_f64", deserialize_with = "deserialize_isometry_3_f64")]
     pub transform: Isometry3<f64>,
     // pub json_metadata: String,
     pub metadata: Value
@@ -89,7 +302,7 @@ impl TransformStamped {
     pub fn default() -> TransformStamped {
         TransformStamped {
             active: true,
-            time_stamp: Instant::now(),
+            time_stamp: SystemTime::now(),
             parent_frame_id: "".to_string(),
             child_frame_id: "".to_string(),
             transform: Isometry3::default(),
@@ -97,4 +310,226 @@ impl TransformStamped {
             // json_metadata: "".to_string()
         }
     }
-}
\ No newline at end of file
+}
+
+/// Represents the type of update to perform on a transform.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpdateType {
+    Add,
+    Move,
+    // Sets the whole transform of an existing frame, parent and metadata included
+    Replace,
+    Remove,
+    Rename,
+    Reparent(ReparentMode),
+    Clone,
+    DeleteAll,
+}
+
+/// How a frame is placed under its new parent when it is reparented.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum ReparentMode {
+    /// The frame stays where it is in the world, its transform is recomputed relative to the new parent.
+    #[default]
+    KeepWorldPose,
+    /// The frame keeps its transform, so it moves along with the new parent.
+    KeepLocalOffset,
+}
+
+/// Emitted to the subscribers of a space tree server whenever a change is committed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpaceTreeEvent {
+    FrameAdded(String),
+    FrameMoved(String),
+    FrameReplaced(String),
+    FrameRemoved(String),
+    FrameRenamed { name: String, rename_to: String },
+    FrameReparented { name: String, parent_frame_id: String },
+    AllDeleted,
+}
+
+/// The frames queued for update after the scenario directory changed, and the files that couldn't be loaded.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ScenarioReloadReport {
+    pub added: Vec<String>,
+    pub moved: Vec<String>,
+    pub removed: Vec<String>,
+    pub failed: Vec<(String, TransformError)>,
+}
+
+/// The frames a committed batch of updates changed, as they were before and after it.
+/// A frame that didn't exist on one side is None there.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BufferDiff {
+    pub before: HashMap<String, Option<TransformStamped>>,
+    pub after: HashMap<String, Option<TransformStamped>>,
+}
+
+/// Holds information about a queued transform update.
+#[derive(Clone, Debug)]
+pub struct UpdateContext {
+    pub name: String,
+    pub update_type: UpdateType,
+    pub transform: TransformStamped,
+}
+
+// The frames that will exist once the queued updates are applied in order,
+// so that chained edits on one frame can be validated when they are queued.
+pub fn frames_after_updates(
+    buffer: &HashMap<String, TransformStamped>,
+    pending_updates: &[UpdateContext],
+) -> HashSet<String> {
+    let mut frames: HashSet<String> = buffer.keys().cloned().collect();
+    for update in pending_updates {
+        match update.update_type {
+            UpdateType::Add => {
+                frames.insert(update.name.clone());
+            }
+            UpdateType::Remove => {
+                frames.remove(&update.name);
+            }
+            UpdateType::Rename => {
+                if frames.remove(&update.name) {
+                    frames.insert(update.transform.child_frame_id.clone());
+                }
+            }
+            UpdateType::Clone => {
+                if frames.contains(&update.name) {
+                    frames.insert(update.transform.child_frame_id.clone());
+                }
+            }
+            UpdateType::DeleteAll => frames.clear(),
+            UpdateType::Move | UpdateType::Replace | UpdateType::Reparent(_) => (),
+        }
+    }
+    frames
+}
+
+// The external frames that are not ours, plus our frames as they will be once the queued updates are applied.
+pub fn all_frames_after_updates(
+    buffer: &HashMap<String, TransformStamped>,
+    external: &HashMap<String, TransformStamped>,
+    frames: &HashSet<String>,
+) -> HashSet<String> {
+    external
+        .keys()
+        .filter(|frame| !buffer.contains_key(*frame))
+        .chain(frames.iter())
+        .cloned()
+        .collect()
+}
+
+/// The outcome of a single pending update when the changes were applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateReport {
+    pub name: String,
+    pub update_type: UpdateType,
+    // The new name, new parent or clone name, depending on the update type
+    pub target: Option<String>,
+    pub applied: bool,
+    pub error: Option<TransformError>,
+}
+
+impl UpdateReport {
+    pub fn new(update_context: &UpdateContext, result: Result<(), TransformError>) -> UpdateReport {
+        let target = match update_context.update_type {
+            UpdateType::Rename | UpdateType::Clone => Some(update_context.transform.child_frame_id.clone()),
+            UpdateType::Reparent(_) | UpdateType::Replace => Some(update_context.transform.parent_frame_id.clone()),
+            _ => None,
+        };
+        UpdateReport {
+            name: update_context.name.clone(),
+            update_type: update_context.update_type.clone(),
+            target,
+            applied: result.is_ok(),
+            error: result.err(),
+        }
+    }
+}
+
+/// The outcome of all pending updates in one apply_changes call, in the order they were processed.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ApplyReport {
+    pub updates: Vec<UpdateReport>,
+}
+
+impl ApplyReport {
+    pub fn is_success(&self) -> bool {
+        self.updates.iter().all(|update| update.applied)
+    }
+
+    pub fn applied(&self) -> Vec<&UpdateReport> {
+        self.updates.iter().filter(|update| update.applied).collect()
+    }
+
+    pub fn rejected(&self) -> Vec<&UpdateReport> {
+        self.updates.iter().filter(|update| !update.applied).collect()
+    }
+
+    pub fn errors(&self) -> Vec<&TransformError> {
+        self.updates.iter().filter_map(|update| update.error.as_ref()).collect()
+    }
+
+    // Mark the updates that went through as not applied, because the transaction was rejected.
+    pub fn roll_back(&mut self, error: TransformError) {
+        for update in self.updates.iter_mut().filter(|update| update.applied) {
+            update.applied = false;
+            update.error = Some(error.clone());
+        }
+    }
+}
+
+// Why a transaction can't be committed: one of its updates was rejected,
+// or the buffer it would produce contains a cycle.
+pub fn transaction_error(
+    report: &ApplyReport,
+    buffer: &HashMap<String, TransformStamped>,
+) -> Option<TransformError> {
+    if !report.is_success() {
+        return Some(TransformError::RolledBack);
+    }
+    find_cyclic_frame(buffer, &build_children_index(buffer)).map(TransformError::CycleDetected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transform_stamped_serde_round_trip() {
+        let mut frame = TransformStamped::default();
+        frame.child_frame_id = "a".to_string();
+        frame.parent_frame_id = "world".to_string();
+        frame.time_stamp = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        frame.transform = Isometry3::new(Vector3::new(1.0, 2.0, 3.0), Vector3::new(0.1, 0.2, 0.3));
+        frame.metadata = serde_json::json!({"mesh": "a.stl"});
+
+        let json = serde_json::to_value(&frame).unwrap();
+        assert_eq!(json["time_stamp"], serde_json::json!({"sec": 1_700_000_000, "nanosec": 123_456_789}));
+        assert_eq!(json["transform"]["translation"], serde_json::json!({"x": 1.0, "y": 2.0, "z": 3.0}));
+
+        let loaded: TransformStamped = serde_json::from_value(json).unwrap();
+        assert_eq!(loaded.time_stamp, frame.time_stamp);
+        assert_eq!(loaded.child_frame_id, frame.child_frame_id);
+        assert_eq!(loaded.metadata, frame.metadata);
+        assert!((loaded.transform.translation.vector - frame.transform.translation.vector).norm() < 1e-12);
+        assert!(loaded.transform.rotation.angle_to(&frame.transform.rotation) < 1e-12);
+    }
+
+    #[test]
+    fn test_json_time_before_epoch() {
+        let time = UNIX_EPOCH - Duration::new(1, 250_000_000);
+        let json = system_time_to_json_time(&time);
+        assert_eq!(json, JsonTime { sec: -2, nanosec: 750_000_000 });
+        assert_eq!(json_time_to_system_time(&json), time);
+    }
+
+    #[cfg(feature = "ros")]
+    #[test]
+    fn test_ros_time_to_system_time() {
+        let stamp = Time { sec: 1_700_000_000, nanosec: 5 };
+        assert_eq!(ros_time_to_system_time(&stamp), UNIX_EPOCH + Duration::new(1_700_000_000, 5));
+        let before = SystemTime::now();
+        assert!(ros_time_to_system_time(&Time::default()) >= before);
+    }
+}
@@ -2,21 +2,45 @@ pub static STATIC_TF_BROADCAST_RATE: u64 = 100;
 pub static ACTIVE_TF_BROADCAST_RATE: u64 = 10;
 pub static MAX_TRANSFORM_CHAIN: u64 = 1000;
 pub static MAX_RECURSION_DEPTH: u64 = 1000;
+pub static TRANSFORM_CACHE_TIME: u64 = 10000;
+pub static UNDO_DEPTH: u64 = 100;
+pub static EVENT_CHANNEL_CAPACITY: u64 = 1000;
+pub static SCENARIO_POLL_RATE: u64 = 200;
+pub static REDIS_KEY_PREFIX: &str = "r2r_transforms";
 
 pub mod core;
 pub use core::structs::*;
 pub use core::space_tree::*;
+pub use core::store::*;
+pub use core::errors::*;
 
 pub mod utils;
 pub use utils::cycles::*;
+pub use utils::events::*;
+pub use utils::history::*;
+pub use utils::index::*;
 pub use utils::lookup::*;
 pub use utils::loading::*;
+pub use utils::poses::*;
 pub use utils::treeviz::*;
+pub use utils::undo::*;
+pub use utils::urdf::*;
+pub use utils::watcher::*;
 
 // This conditionally includes a module which implements r2r support.
-// #[cfg(feature = "ros")]
+#[cfg(feature = "ros")]
 pub mod ros;
-// #[cfg(feature = "ros")]
+#[cfg(feature = "ros")]
 pub use ros::broadcasters::*;
+#[cfg(feature = "ros")]
 pub use ros::listeners::*;
-pub use ros::space_tree_ros::*;
\ No newline at end of file
+#[cfg(feature = "ros")]
+pub use ros::space_tree_ros::*;
+
+// This conditionally includes a module which mirrors the space tree into Redis.
+#[cfg(feature = "redis")]
+pub mod redis;
+#[cfg(feature = "redis")]
+pub use crate::redis::space_tree_redis::*;
+#[cfg(feature = "redis")]
+pub use crate::redis::store_redis::*;
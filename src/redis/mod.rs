@@ -0,0 +1,2 @@
+pub mod space_tree_redis;
+pub mod store_redis;
\ No newline at end of file
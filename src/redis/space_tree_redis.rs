@@ -0,0 +1,339 @@
+use crate::*;
+use ::redis::{aio::MultiplexedConnection, AsyncCommands, Client, RedisError};
+use futures::StreamExt;
+use std::collections::HashMap;
+use tokio::sync::broadcast::error::RecvError;
+
+// A shared space tree is a Redis hash with one serialized frame per field,
+// and a channel on which the names of the changed frames are published.
+pub fn redis_frames_key(space: &str) -> String {
+    format!("{}:{}:frames", REDIS_KEY_PREFIX, space)
+}
+
+pub fn redis_updates_channel(space: &str) -> String {
+    format!("{}:{}:updates", REDIS_KEY_PREFIX, space)
+}
+
+// Frames are stamped again whenever they are applied, so the time stamp is ignored.
+// Without this, two processes would keep sending the same frame back and forth.
+pub fn same_frame(a: &TransformStamped, b: &TransformStamped) -> bool {
+    a.active == b.active
+        && a.parent_frame_id == b.parent_frame_id
+        && a.child_frame_id == b.child_frame_id
+        && a.transform == b.transform
+        && a.metadata == b.metadata
+}
+
+// The frames that have to change for the buffer to match the remote frames,
+// where None means that the frame was removed remotely.
+pub fn remote_frames_diff(
+    buffer: &HashMap<String, TransformStamped>,
+    remote: &HashMap<String, Option<TransformStamped>>,
+) -> Option<BufferDiff> {
+    let mut diff = BufferDiff::default();
+    for (name, frame) in remote {
+        let local = buffer.get(name);
+        let changed = match (local, frame) {
+            (Some(local), Some(frame)) => !same_frame(local, frame),
+            (None, None) => false,
+            _ => true,
+        };
+        if changed {
+            diff.before.insert(name.clone(), local.cloned());
+            diff.after.insert(name.clone(), frame.clone());
+        }
+    }
+    if diff.before.is_empty() {
+        None
+    } else {
+        Some(diff)
+    }
+}
+
+// The frames whose Redis entries have to be checked after the event.
+// None means all of them.
+fn event_frames(event: &SpaceTreeEvent) -> Option<Vec<String>> {
+    match event {
+        SpaceTreeEvent::FrameAdded(name)
+        | SpaceTreeEvent::FrameMoved(name)
+        | SpaceTreeEvent::FrameReplaced(name)
+        | SpaceTreeEvent::FrameRemoved(name)
+        | SpaceTreeEvent::FrameReparented { name, .. } => Some(vec![name.clone()]),
+        SpaceTreeEvent::FrameRenamed { name, rename_to } => Some(vec![name.clone(), rename_to.clone()]),
+        SpaceTreeEvent::AllDeleted => None,
+    }
+}
+
+fn redis_error(context: &str, e: RedisError) -> TransformError {
+    TransformError::Io(format!("{} failed with: '{}'.", context, e))
+}
+
+fn parse_frame(name: &str, value: &str) -> Option<TransformStamped> {
+    match serde_json::from_str(value) {
+        Ok(frame) => Some(frame),
+        Err(e) => {
+            log::warn!(target: "r2r_transforms", "Ignoring the Redis entry of frame '{}', parsing failed with: '{}'.", name, e);
+            None
+        }
+    }
+}
+
+async fn get_remote_frames(
+    connection: &mut MultiplexedConnection,
+    key: &str,
+    names: &[String],
+) -> Result<HashMap<String, Option<TransformStamped>>, RedisError> {
+    let mut remote = HashMap::new();
+    for name in names {
+        let value: Option<String> = connection.hget(key, name).await?;
+        remote.insert(name.clone(), value.and_then(|value| parse_frame(name, &value)));
+    }
+    Ok(remote)
+}
+
+// Write the local state of the frames into Redis and publish the names of the ones that changed.
+async fn push_frames(
+    connection: &mut MultiplexedConnection,
+    space: &str,
+    buffer: &HashMap<String, TransformStamped>,
+    names: &[String],
+) -> Result<(), RedisError> {
+    let key = redis_frames_key(space);
+    let remote = get_remote_frames(connection, &key, names).await?;
+    let mut changed = vec![];
+    for name in names {
+        match (buffer.get(name), &remote[name]) {
+            (Some(local), Some(frame)) if same_frame(local, frame) => continue,
+            (None, None) => continue,
+            (Some(local), _) => {
+                let value = serde_json::to_string(local).unwrap_or_default();
+                let _: () = connection.hset(&key, name, value).await?;
+            }
+            (None, Some(_)) => {
+                let _: () = connection.hdel(&key, name).await?;
+            }
+        }
+        changed.push(name.clone());
+    }
+    if !changed.is_empty() {
+        let message = serde_json::to_string(&changed).unwrap_or_default();
+        let _: () = connection.publish(redis_updates_channel(space), message).await?;
+    }
+    Ok(())
+}
+
+impl<S: TransformStore> SpaceTreeServer<S> {
+    /// Shares the local frames with every process connected to the same space in Redis.
+    /// Frames that exist in Redis replace the local ones, the other local frames are added.
+    /// From then on, committed changes are mirrored into Redis, and the changes of
+    /// the other processes are applied to the local buffer as they are published.
+    pub async fn connect_redis(&self, url: &str, space: &str) -> Result<(), TransformError> {
+        let client = Client::open(url).map_err(|e| redis_error("Opening the Redis client", e))?;
+        let mut connection = client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(|e| redis_error("Connecting to Redis", e))?;
+        let mut pubsub = client
+            .get_async_pubsub()
+            .await
+            .map_err(|e| redis_error("Connecting to Redis pub/sub", e))?;
+        pubsub
+            .subscribe(redis_updates_channel(space))
+            .await
+            .map_err(|e| redis_error("Subscribing to the Redis updates", e))?;
+        // Subscribe before the initial sync, so that no change falls in between
+        let mut events = self.subscribe();
+
+        let key = redis_frames_key(space);
+        let entries: HashMap<String, String> = connection
+            .hgetall(&key)
+            .await
+            .map_err(|e| redis_error("Reading the shared frames", e))?;
+        let remote: HashMap<String, Option<TransformStamped>> = entries
+            .iter()
+            .filter_map(|(name, value)| parse_frame(name, value).map(|frame| (name.clone(), Some(frame))))
+            .collect();
+        self.apply_remote_frames(&remote);
+        let buffer = self.store.snapshot();
+        let local_only: Vec<String> = buffer.keys().filter(|name| !remote.contains_key(*name)).cloned().collect();
+        push_frames(&mut connection, space, &buffer, &local_only)
+            .await
+            .map_err(|e| redis_error("Writing the local frames", e))?;
+        log::info!(target: "r2r_transforms", "Connected space tree '{}' to Redis space '{}'.", self.name, space);
+
+        let server = self.clone();
+        let space_clone = space.to_string();
+        let mut push_connection = connection.clone();
+        tokio::task::spawn(async move {
+            loop {
+                let names = match events.recv().await {
+                    Ok(event) => event_frames(&event),
+                    // Missed some events, so everything has to be checked
+                    Err(RecvError::Lagged(_)) => None,
+                    Err(RecvError::Closed) => break,
+                };
+                let buffer = server.store.snapshot();
+                let result = match names {
+                    Some(names) => push_frames(&mut push_connection, &space_clone, &buffer, &names).await,
+                    None => {
+                        let remote: Result<Vec<String>, RedisError> =
+                            push_connection.hkeys(redis_frames_key(&space_clone)).await;
+                        match remote {
+                            Ok(mut names) => {
+                                names.extend(buffer.keys().filter(|name| !names.contains(name)).cloned().collect::<Vec<_>>());
+                                push_frames(&mut push_connection, &space_clone, &buffer, &names).await
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                };
+                if let Err(e) = result {
+                    log::error!(target: "r2r_transforms", "Mirroring frames into Redis failed with: '{}'.", e);
+                }
+            }
+        });
+
+        let server = self.clone();
+        tokio::task::spawn(async move {
+            let mut messages = pubsub.on_message();
+            while let Some(message) = messages.next().await {
+                let names: Vec<String> = match message
+                    .get_payload::<String>()
+                    .map_err(|e| e.to_string())
+                    .and_then(|payload| serde_json::from_str(&payload).map_err(|e| e.to_string()))
+                {
+                    Ok(names) => names,
+                    Err(e) => {
+                        log::warn!(target: "r2r_transforms", "Ignoring a Redis update, parsing failed with: '{}'.", e);
+                        continue;
+                    }
+                };
+                match get_remote_frames(&mut connection, &key, &names).await {
+                    Ok(remote) => server.apply_remote_frames(&remote),
+                    Err(e) => log::error!(target: "r2r_transforms", "Reading shared frames failed with: '{}'.", e),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    // Remote changes were already validated by the process that made them, so they
+    // are written directly into the buffer. They are not undoable, but still send events.
+    // The pending updates are held like in every other commit, so no local commit is lost.
+    fn apply_remote_frames(&self, remote: &HashMap<String, Option<TransformStamped>>) {
+        let _pending_updates = self.pending_updates.lock().unwrap();
+        let old_buffer = self.store.snapshot();
+        let diff = match remote_frames_diff(&old_buffer, remote) {
+            Some(diff) => diff,
+            None => return,
+        };
+        let mut buffer = old_buffer.clone();
+        for (name, frame) in &diff.after {
+            match frame {
+                Some(frame) => buffer.insert(name.clone(), frame.clone()),
+                None => buffer.remove(name),
+            };
+        }
+        self.commit(&old_buffer, buffer);
+        log::info!(target: "r2r_transforms", "Applied {} frames changed in Redis.", diff.after.len());
+        self.publish_events(events_from_diff(&diff.before, &diff.after));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::Duration;
+
+    fn frame(child: &str, parent: &str, x: f64) -> TransformStamped {
+        let mut frame = TransformStamped::default();
+        frame.child_frame_id = child.to_string();
+        frame.parent_frame_id = parent.to_string();
+        frame.transform.translation.x = x;
+        frame
+    }
+
+    #[test]
+    fn test_remote_frames_diff() {
+        let buffer = HashMap::from([
+            ("a".to_string(), frame("a", "world", 1.0)),
+            ("b".to_string(), frame("b", "a", 1.0)),
+        ]);
+        let remote = HashMap::from([
+            // Only the time stamp differs
+            ("a".to_string(), Some(frame("a", "world", 1.0))),
+            ("b".to_string(), Some(frame("b", "a", 2.0))),
+            ("c".to_string(), Some(frame("c", "a", 1.0))),
+            ("d".to_string(), None),
+        ]);
+
+        let diff = remote_frames_diff(&buffer, &remote).unwrap();
+        let mut names: Vec<&String> = diff.after.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["b", "c"]);
+        assert!(diff.before["c"].is_none());
+
+        let remote = HashMap::from([("b".to_string(), None)]);
+        let diff = remote_frames_diff(&buffer, &remote).unwrap();
+        assert!(diff.after["b"].is_none());
+        assert!(remote_frames_diff(&buffer, &HashMap::new()).is_none());
+    }
+
+    #[test]
+    fn test_apply_remote_frames() {
+        let server = SpaceTreeServer::new("test");
+        server.insert_transform("a", frame("a", "world", 1.0)).unwrap();
+        server.apply_changes();
+        let mut events = server.subscribe();
+
+        server.apply_remote_frames(&HashMap::from([
+            ("a".to_string(), None),
+            ("b".to_string(), Some(frame("b", "world", 1.0))),
+        ]));
+
+        let buffer = server.store.snapshot();
+        assert!(!buffer.contains_key("a"));
+        assert!(buffer.contains_key("b"));
+        assert_eq!(events.try_recv().unwrap(), SpaceTreeEvent::FrameRemoved("a".to_string()));
+        assert_eq!(events.try_recv().unwrap(), SpaceTreeEvent::FrameAdded("b".to_string()));
+        // Remote changes are not undoable locally
+        assert_eq!(server.undo_stack.lock().unwrap().len(), 1);
+    }
+
+    // Needs a local redis-server: cargo test --features redis -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn test_two_servers_share_frames() {
+        let url = std::env::var("REDIS_URL").unwrap_or("redis://127.0.0.1:6379".to_string());
+        let space = format!("test_{}", std::process::id());
+
+        let first = SpaceTreeServer::new("first");
+        first.insert_transform("a", frame("a", "world", 1.0)).unwrap();
+        first.apply_changes();
+        first.connect_redis(&url, &space).await.unwrap();
+
+        let second = SpaceTreeServer::new("second");
+        second.connect_redis(&url, &space).await.unwrap();
+        assert!(second.store.snapshot().contains_key("a"));
+
+        second.move_transform("a", frame("a", "world", 2.0).transform).unwrap();
+        second.insert_transform("b", frame("b", "a", 1.0)).unwrap();
+        second.apply_changes();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let buffer = first.store.snapshot();
+        assert_eq!(buffer["a"].transform.translation.x, 2.0);
+        assert!(buffer.contains_key("b"));
+
+        first.remove_transform("b").unwrap();
+        first.apply_changes();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!second.store.snapshot().contains_key("b"));
+
+        let client = Client::open(url.as_str()).unwrap();
+        let mut connection = client.get_multiplexed_async_connection().await.unwrap();
+        let _: () = connection.del(redis_frames_key(&space)).await.unwrap();
+    }
+}
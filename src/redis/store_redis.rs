@@ -0,0 +1,206 @@
+use crate::*;
+use ::redis::{Client, Commands, Connection, RedisError};
+use nalgebra::Isometry3;
+use std::{
+    collections::HashMap,
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+/// Keeps the frames in Redis, so that every process that opens the same space shares them.
+/// Commits are published like the changes mirrored by connect_redis, so both can be mixed.
+/// Reads are served from a local copy, which is dropped whenever a change is published,
+/// so a change made by another process is seen as soon as its message arrives.
+#[derive(Clone)]
+pub struct RedisStore {
+    pub space: String,
+    connection: Arc<Mutex<Connection>>,
+    cache: Arc<Mutex<RedisCache>>,
+}
+
+// The frames as they were last read from Redis and the poses resolved from them.
+// If the subscription to the updates fails, nothing is cached and every read goes to Redis.
+#[derive(Default)]
+struct RedisCache {
+    subscribed: bool,
+    frames: Option<HashMap<String, TransformStamped>>,
+    poses: PoseCache,
+}
+
+impl RedisStore {
+    pub fn open(url: &str, space: &str) -> Result<Self, TransformError> {
+        let client = Client::open(url)
+            .map_err(|e| TransformError::Io(format!("Connecting to Redis failed with: '{}'.", e)))?;
+        let connection = client
+            .get_connection()
+            .map_err(|e| TransformError::Io(format!("Connecting to Redis failed with: '{}'.", e)))?;
+        let cache = Arc::new(Mutex::new(RedisCache::default()));
+        watch_updates(&client, space, &cache)
+            .map_err(|e| TransformError::Io(format!("Subscribing to the Redis updates failed with: '{}'.", e)))?;
+        Ok(Self {
+            space: space.to_string(),
+            connection: Arc::new(Mutex::new(connection)),
+            cache,
+        })
+    }
+
+    fn read_frames(&self) -> Result<HashMap<String, TransformStamped>, RedisError> {
+        let entries: HashMap<String, String> = self.connection.lock().unwrap().hgetall(redis_frames_key(&self.space))?;
+        Ok(entries
+            .iter()
+            .filter_map(|(name, value)| serde_json::from_str(value).ok().map(|frame| (name.clone(), frame)))
+            .collect())
+    }
+
+    // Run f on the cached frames, reading them from Redis first if a change dropped them.
+    // The cache stays locked during the read, so an update that arrives meanwhile drops it again.
+    fn cached<T>(&self, f: impl FnOnce(&HashMap<String, TransformStamped>, &mut PoseCache) -> T) -> T {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.frames.is_none() {
+            match self.read_frames() {
+                Ok(frames) if cache.subscribed => cache.frames = Some(frames),
+                Ok(frames) => return f(&frames, &mut PoseCache::new()),
+                Err(e) => {
+                    log::error!(target: "r2r_transforms", "Reading the frames from Redis failed with: '{}'.", e);
+                    return f(&HashMap::new(), &mut PoseCache::new());
+                }
+            }
+        }
+        let RedisCache { frames, poses, .. } = &mut *cache;
+        f(frames.as_ref().unwrap(), poses)
+    }
+}
+
+// Drop the cache whenever a change to the space is published. Subscribed before the store
+// is handed out, so that no change falls in between. The thread stops once the store is dropped.
+fn watch_updates(client: &Client, space: &str, cache: &Arc<Mutex<RedisCache>>) -> Result<(), RedisError> {
+    let mut connection = client.get_connection()?;
+    let channel = redis_updates_channel(space);
+    let weak_cache = Arc::downgrade(cache);
+    let (subscribed, subscription) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut pubsub = connection.as_pubsub();
+        let result = pubsub
+            .subscribe(&channel)
+            .and_then(|_| pubsub.set_read_timeout(Some(Duration::from_secs(1))));
+        let failed = result.is_err();
+        let _ = subscribed.send(result);
+        if failed {
+            return;
+        }
+        loop {
+            let message = pubsub.get_message();
+            let cache = match weak_cache.upgrade() {
+                Some(cache) => cache,
+                None => return,
+            };
+            let mut cache = cache.lock().unwrap();
+            match message {
+                Ok(_) => {
+                    cache.frames = None;
+                    cache.poses.clear();
+                }
+                Err(e) if e.is_timeout() => (),
+                Err(e) => {
+                    log::error!(target: "r2r_transforms", "Watching the Redis updates failed with: '{}', frames are no longer cached.", e);
+                    *cache = RedisCache::default();
+                    return;
+                }
+            }
+        }
+    });
+    subscription.recv().unwrap_or_else(|_| Err(RedisError::from(std::io::Error::other("the subscriber stopped"))))?;
+    cache.lock().unwrap().subscribed = true;
+    Ok(())
+}
+
+impl TransformStore for RedisStore {
+    fn snapshot(&self) -> HashMap<String, TransformStamped> {
+        self.cached(|frames, _| frames.clone())
+    }
+
+    fn get(&self, name: &str) -> Option<TransformStamped> {
+        self.cached(|frames, _| frames.get(name).cloned())
+    }
+
+    // Only the changed frames are written, in one transaction. The cached frames take the same
+    // changes, the changes of other processes drop them when they are published.
+    fn commit(&self, _buffer: HashMap<String, TransformStamped>, diff: &BufferDiff) {
+        let key = redis_frames_key(&self.space);
+        let mut pipe = ::redis::pipe();
+        pipe.atomic();
+        let mut names: Vec<&String> = diff.after.keys().collect();
+        names.sort();
+        for name in &names {
+            match &diff.after[*name] {
+                Some(frame) => pipe.hset(&key, *name, serde_json::to_string(frame).unwrap_or_default()).ignore(),
+                None => pipe.hdel(&key, *name).ignore(),
+            };
+        }
+        pipe.publish(redis_updates_channel(&self.space), serde_json::to_string(&names).unwrap_or_default())
+            .ignore();
+        let result: Result<(), RedisError> = pipe.query(&mut *self.connection.lock().unwrap());
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.poses.clear();
+        match (&result, cache.frames.as_mut()) {
+            (Ok(()), Some(frames)) => {
+                for (name, frame) in &diff.after {
+                    match frame {
+                        Some(frame) => frames.insert(name.clone(), frame.clone()),
+                        None => frames.remove(name),
+                    };
+                }
+            }
+            _ => cache.frames = None,
+        }
+        if let Err(e) = result {
+            log::error!(target: "r2r_transforms", "Writing the frames to Redis failed with: '{}'.", e);
+        }
+    }
+
+    fn root_pose(&self, name: &str) -> Result<(String, Isometry3<f64>), TransformError> {
+        self.cached(|frames, poses| cached_root_pose(name, frames, poses))
+    }
+
+    fn relative_pose(&self, parent: &str, child: &str) -> Option<(Isometry3<f64>, bool)> {
+        self.cached(|frames, poses| relative_root_pose(parent, child, frames, poses))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn eventually(condition: impl Fn() -> bool) -> bool {
+        (0..100).any(|_| {
+            std::thread::sleep(Duration::from_millis(10));
+            condition()
+        })
+    }
+
+    // Needs a local redis-server: cargo test --features redis -- --ignored
+    #[test]
+    #[ignore]
+    fn test_redis_store_is_shared() {
+        let url = std::env::var("REDIS_URL").unwrap_or("redis://127.0.0.1:6379".to_string());
+        let space = format!("store_test_{}", std::process::id());
+
+        let first = SpaceTreeServer::with_store("first", RedisStore::open(&url, &space).unwrap());
+        let second = SpaceTreeServer::with_store("second", RedisStore::open(&url, &space).unwrap());
+        let mut a = TransformStamped::default();
+        a.parent_frame_id = "world".to_string();
+        a.child_frame_id = "a".to_string();
+        // The other store has read the space before, so it only sees the change once it is published
+        assert!(second.store.snapshot().is_empty());
+        first.insert_transform("a", a).unwrap();
+        first.apply_changes();
+        assert!(eventually(|| second.store.get("a").is_some()));
+
+        second.remove_transform("a").unwrap();
+        second.apply_changes();
+        assert!(second.store.snapshot().is_empty());
+        assert!(eventually(|| first.store.snapshot().is_empty()));
+    }
+}
@@ -1,10 +1,11 @@
 use crate::*;
 use futures::{Stream, StreamExt};
+use r2r::sensor_msgs::msg::JointState;
 use r2r::tf2_msgs::msg::TFMessage;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Arc, Mutex};
-use tokio::time::Instant;
+use std::time::Duration;
 
 // updates the buffer with active frames from the tf topic
 // TODO: if a stale active frame is on the tf for some reason, don't include it
@@ -13,25 +14,33 @@ pub async fn active_tf_listener_callback(
     mut subscriber: impl Stream<Item = TFMessage> + Unpin,
     local_buffer: &Arc<Mutex<HashMap<String, TransformStamped>>>,
     global_buffer: &Arc<Mutex<HashMap<String, TransformStamped>>>,
+    history: &Arc<Mutex<HashMap<String, VecDeque<TransformStamped>>>>,
+    cache_time: Duration,
 ) -> Result<(), Box<dyn std::error::Error>> {
     loop {
         match subscriber.next().await {
             Some(message) => {
                 let mut frames = global_buffer.lock().unwrap().clone();
                 let local_buffer = local_buffer.lock().unwrap().clone();
+                let mut history = history.lock().unwrap();
                 message.transforms.iter().for_each(|t| {
                     if !local_buffer.contains_key(&t.child_frame_id) {
-                        frames.insert(
-                            t.child_frame_id.clone(),
-                            TransformStamped {
-                                active: true,
-                                time_stamp: Instant::now(), // TODO: use t.header.stamp,
-                                parent_frame_id: t.header.frame_id.clone(),
-                                child_frame_id: t.child_frame_id.clone(),
-                                transform: ros_transform_to_isometry(t.transform.clone()),
-                                metadata: Value::default(), // json_metadata: "".to_string()
-                            },
-                        );
+                        let transform = TransformStamped {
+                            active: true,
+                            time_stamp: ros_time_to_system_time(&t.header.stamp),
+                            parent_frame_id: t.header.frame_id.clone(),
+                            child_frame_id: t.child_frame_id.clone(),
+                            transform: ros_transform_to_isometry(t.transform.clone()),
+                            metadata: Value::default(), // json_metadata: "".to_string()
+                        };
+                        // Delayed messages only fill in the history, the buffer keeps the newest sample
+                        record_transform_sample(&mut history, &transform, cache_time);
+                        let newest = frames
+                            .get(&t.child_frame_id)
+                            .is_none_or(|current| current.time_stamp <= transform.time_stamp);
+                        if newest {
+                            frames.insert(t.child_frame_id.clone(), transform);
+                        }
                     }
                 });
                 *global_buffer.lock().unwrap() = frames;
@@ -47,25 +56,28 @@ pub async fn static_tf_listener_callback(
     mut subscriber: impl Stream<Item = TFMessage> + Unpin,
     local_buffer: &Arc<Mutex<HashMap<String, TransformStamped>>>,
     global_buffer: &Arc<Mutex<HashMap<String, TransformStamped>>>,
+    history: &Arc<Mutex<HashMap<String, VecDeque<TransformStamped>>>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     loop {
         match subscriber.next().await {
             Some(message) => {
                 let mut frames = global_buffer.lock().unwrap().clone();
                 let local_buffer = local_buffer.lock().unwrap().clone();
+                let mut history = history.lock().unwrap();
+                // Static frames are valid for all times, so they have no history and
+                // lookups in the past use the latest one, whenever it was stamped
                 message.transforms.iter().for_each(|t| {
                     if !local_buffer.contains_key(&t.child_frame_id) {
-                        frames.insert(
-                            t.child_frame_id.clone(),
-                            TransformStamped {
-                                active: false,
-                                time_stamp: Instant::now(), // TODO: use t.header.stamp,
-                                parent_frame_id: t.header.frame_id.clone(),
-                                child_frame_id: t.child_frame_id.clone(),
-                                transform: ros_transform_to_isometry(t.transform.clone()),
-                                metadata: Value::default(),
-                            },
-                        );
+                        let transform = TransformStamped {
+                            active: false,
+                            time_stamp: ros_time_to_system_time(&t.header.stamp),
+                            parent_frame_id: t.header.frame_id.clone(),
+                            child_frame_id: t.child_frame_id.clone(),
+                            transform: ros_transform_to_isometry(t.transform.clone()),
+                            metadata: Value::default(),
+                        };
+                        history.remove(&t.child_frame_id);
+                        frames.insert(t.child_frame_id.clone(), transform);
                     }
                 });
                 *global_buffer.lock().unwrap() = frames;
@@ -76,3 +88,39 @@ pub async fn static_tf_listener_callback(
         }
     }
 }
+
+// moves the joint frames to the positions from the joint_states topic and commits them,
+// past the pending updates and the undo stack. Joints that weren't imported, like the ones
+// of other robots on the same topic, are only warned about once.
+pub async fn joint_state_listener_callback(
+    mut subscriber: impl Stream<Item = JointState> + Unpin,
+    server: &RosSpaceTreeServer,
+    scope: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut warned = HashSet::new();
+    loop {
+        match subscriber.next().await {
+            Some(message) => {
+                let positions: HashMap<String, f64> = message
+                    .name
+                    .into_iter()
+                    .zip(message.position)
+                    .collect();
+                if let Err(errors) = server.commit_joint_positions(scope, &positions) {
+                    errors.iter().for_each(|e| {
+                        let new = match e {
+                            TransformError::FrameNotFound(name) => warned.insert(name.clone()),
+                            _ => true,
+                        };
+                        if new {
+                            r2r::log_warn!("r2r_transforms", "Joint state not applied: '{}'.", e)
+                        }
+                    });
+                }
+            }
+            None => {
+                r2r::log_error!("r2r_transforms", "Subscriber did not get the message?");
+            }
+        }
+    }
+}
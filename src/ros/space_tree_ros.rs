@@ -1,53 +1,116 @@
-use nalgebra::Isometry3;
 use crate::*;
+use nalgebra::Isometry3;
 use std::{
-    collections::HashMap, sync::{Arc, Mutex}
+    collections::HashMap, ops::Deref, sync::{Arc, Mutex}, time::Duration
 };
-use log;
 
 
 // use r2r::std_msgs::msg::Header;
+use r2r::sensor_msgs::msg::JointState;
 use r2r::tf2_msgs::msg::TFMessage;
 // use r2r::Context;
 use r2r::QosProfile;
 
 
-/// Represents the type of update to perform on a transform.
-#[derive(Clone, Debug)]
-enum UpdateType {
-    Add,
-    Move,
-    Remove,
-    Rename,
-    Reparent,
-    Clone,
-    DeleteAll,
+/// Keeps our frames in the local buffer, which the tf broadcasters publish, and sees
+/// the frames of the other nodes through the global buffer, which the tf listeners fill.
+/// The buffers are private, the local one only changes through commit so that the
+/// children index and the pose cache stay in step with it.
+#[derive(Clone)]
+pub struct RosStore {
+    local_buffer: Arc<Mutex<HashMap<String, TransformStamped>>>,
+    global_buffer: Arc<Mutex<HashMap<String, TransformStamped>>>,
+    // The children index and the pose cache of the local buffer, the global buffer changes too often to keep them
+    children: Arc<Mutex<ChildrenIndex>>,
+    poses: Arc<Mutex<PoseCache>>,
 }
 
-/// Holds information about a transform update.
-#[derive(Clone, Debug)]
-struct UpdateContext {
-    update_type: UpdateType,
-    transform: TransformStamped,
+impl TransformStore for RosStore {
+    fn snapshot(&self) -> HashMap<String, TransformStamped> {
+        self.local_buffer.lock().unwrap().clone()
+    }
+
+    fn get(&self, name: &str) -> Option<TransformStamped> {
+        self.local_buffer.lock().unwrap().get(name).cloned()
+    }
+
+    fn commit(&self, buffer: HashMap<String, TransformStamped>, diff: &BufferDiff) {
+        let mut local_buffer = self.local_buffer.lock().unwrap();
+        let mut children = self.children.lock().unwrap();
+        let mut poses = self.poses.lock().unwrap();
+        for name in diff.after.keys() {
+            invalidate_subtree(&mut poses, name, &children);
+            update_children_index(&mut children, name, local_buffer.get(name), buffer.get(name));
+        }
+        *local_buffer = buffer;
+    }
+
+    fn children(&self) -> ChildrenIndex {
+        self.children.lock().unwrap().clone()
+    }
+
+    fn root_pose(&self, name: &str) -> Result<(String, Isometry3<f64>), TransformError> {
+        let local_buffer = self.local_buffer.lock().unwrap();
+        cached_root_pose(name, &local_buffer, &mut self.poses.lock().unwrap())
+    }
+
+    fn relative_pose(&self, parent: &str, child: &str) -> Option<(Isometry3<f64>, bool)> {
+        let local_buffer = self.local_buffer.lock().unwrap();
+        relative_root_pose(parent, child, &local_buffer, &mut self.poses.lock().unwrap())
+    }
+
+    // The global buffer can still hold our frames from before we took them over
+    fn external(&self) -> HashMap<String, TransformStamped> {
+        let local_buffer = self.local_buffer.lock().unwrap().clone();
+        self.global_buffer
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| !local_buffer.contains_key(*name))
+            .map(|(name, transform)| (name.clone(), transform.clone()))
+            .collect()
+    }
 }
 
-/// A server that maintains a spatial tree buffer of transforms.
+/// A server that maintains a spatial tree buffer of transforms and connects it to /tf and /tf_static.
+/// Updates, lookups and undo are done by the wrapped space tree server, so they behave
+/// exactly like in the core server, with the frames from /tf as external frames.
 #[derive(Clone)]
 pub struct RosSpaceTreeServer {
-    pub name: String,
-    // These are the transforms that we are in control of
-    pub local_buffer: Arc<Mutex<HashMap<String, TransformStamped>>>,
+    pub space_tree: SpaceTreeServer<RosStore>,
     // These are all the transforms that exist when connected to the ROS world via /tf and /tf_static
-    pub global_buffer: Arc<Mutex<HashMap<String, TransformStamped>>>,
-    // We are only allowed to perform updates ont the local buffer
-    pending_updates: Arc<Mutex<HashMap<String, UpdateContext>>>,
+    global_buffer: Arc<Mutex<HashMap<String, TransformStamped>>>,
+}
+
+impl Deref for RosSpaceTreeServer {
+    type Target = SpaceTreeServer<RosStore>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.space_tree
+    }
 }
 
 impl RosSpaceTreeServer {
     pub fn new(name: &str, node: &Arc<Mutex<r2r::Node>>) -> Self {
+        Self::new_with_cache_time(name, node, Duration::from_millis(TRANSFORM_CACHE_TIME))
+    }
+
+    /// Same as new, but sets how far back in time the transform history is kept.
+    pub fn new_with_cache_time(name: &str, node: &Arc<Mutex<r2r::Node>>, cache_time: Duration) -> Self {
 
         let local_buffer = Arc::new(Mutex::new(HashMap::new()));
         let global_buffer = Arc::new(Mutex::new(HashMap::new()));
+        let space_tree = SpaceTreeServer::with_store(
+            name,
+            RosStore {
+                local_buffer: local_buffer.clone(),
+                global_buffer: global_buffer.clone(),
+                children: Arc::new(Mutex::new(HashMap::new())),
+                poses: Arc::new(Mutex::new(HashMap::new())),
+            },
+        )
+        .with_cache_time(cache_time);
+        let history = space_tree.history.clone();
 
         let static_pub_timer = node
             .lock()
@@ -107,11 +170,14 @@ impl RosSpaceTreeServer {
             node.lock().unwrap().subscribe::<TFMessage>("tf", QosProfile::volatile(QosProfile::default())).expect("Failed to initialize active_tf_listener.");
         let local_buffer_clone = local_buffer.clone();
         let global_buffer_clone = global_buffer.clone();
+        let history_clone = history.clone();
         tokio::task::spawn(async move {
             match active_tf_listener_callback(
                 active_tf_listener,
                 &local_buffer_clone,
                 &global_buffer_clone,
+                &history_clone,
+                cache_time,
             )
             .await
             {
@@ -124,11 +190,13 @@ impl RosSpaceTreeServer {
             node.lock().unwrap().subscribe::<TFMessage>("tf_static", QosProfile::volatile(QosProfile::default())).expect("Failed to initialize static_tf_listener.");
         let local_buffer_clone = local_buffer.clone();
         let global_buffer_clone = global_buffer.clone();
+        let history_clone = history.clone();
         tokio::task::spawn(async move {
             match static_tf_listener_callback(
                 static_tf_listener,
                 &local_buffer_clone,
                 &global_buffer_clone,
+                &history_clone,
             )
             .await
             {
@@ -137,245 +205,52 @@ impl RosSpaceTreeServer {
             };
         });
 
-        let local_buffer_clone = local_buffer.clone();
-        let global_buffer_clone = global_buffer.clone();
         Self {
-            name: name.to_string(),
-            local_buffer: local_buffer_clone,
-            global_buffer: global_buffer_clone,
-            pending_updates: Arc::new(Mutex::new(HashMap::new())),
-        }
-    }
-
-    pub fn load_scenario(&self, scenario_path: &str, overlay: bool) {
-        match list_frames_in_dir(scenario_path) {
-            Ok(list) => {
-                let frames = load_new_scenario(&list);
-                if overlay {
-                    frames.values().for_each(|frame| Self::insert_transform(&self, &frame.child_frame_id, frame.clone()));
-                } else {
-                    let buffer = self.local_buffer.lock().unwrap();
-                    frames
-                        .values()
-                        .filter(|frame| buffer
-                        .get(&frame.child_frame_id) == None)
-                        .for_each(|frame| Self::insert_transform(&self, &frame.child_frame_id, frame.clone()));
-                }
-            },
-            Err(_) => return
-        }
-    }
-
-    pub fn insert_transform(&self, name: &str, transform: TransformStamped) {
-        let mut pending_updates = self.pending_updates.lock().unwrap();
-
-        // Add or update the pending update for the transform.
-        let update_context =
-            pending_updates
-                .entry(name.to_string())
-                .or_insert_with(|| UpdateContext {
-                    update_type: UpdateType::Add,
-                    transform: transform.clone(),
-                });
-
-        update_context.update_type = UpdateType::Add;
-        update_context.transform = transform;
-
-        log::info!("Pending update: Insert transform with name '{}'", name);
-    }
-
-    pub fn move_transform(&self, name: &str, pose: Isometry3<f64>) {
-        let buffer = self.local_buffer.lock().unwrap();
-        let mut pending_updates = self.pending_updates.lock().unwrap();
-
-        if !buffer.contains_key(name) {
-            log::info!(
-                "Can't move the frame '{}' to a new pose, buffer doesn't contain it.",
-                name
-            );
-            return;
-        }
-
-        // Move the frame to a new pose
-        let update_context =
-            pending_updates
-                .entry(name.to_string())
-                .or_insert_with(|| UpdateContext {
-                    update_type: UpdateType::Move,
-                    transform: {
-                        let mut tf = TransformStamped::default();
-                        tf.transform = pose;
-                        tf
-                    },
-                });
-
-        update_context.update_type = UpdateType::Move;
-        update_context.transform.transform = pose;
-
-        log::info!("Pending update: Move transform with name '{}'", name);
-    }
-
-    pub fn remove_transform(&self, name: &str) {
-        let buffer = self.local_buffer.lock().unwrap();
-        let mut pending_updates = self.pending_updates.lock().unwrap();
-
-        if !buffer.contains_key(name) {
-            log::info!(
-                "Can't remove the frame '{}', buffer doesn't contain it.",
-                name
-            );
-            return;
+            space_tree,
+            global_buffer,
         }
-
-        pending_updates.insert(
-            name.to_string(),
-            UpdateContext {
-                update_type: UpdateType::Remove,
-                transform: TransformStamped::default()
-            },
-        );
-
-        log::info!("Pending update: Remove transform with name '{}'", name);
-
-    }
-
-    pub fn rename_transform(&self, name: &str, rename_to: &str) {
-        let local_buffer = self.local_buffer.lock().unwrap();
-        let global_buffer = self.global_buffer.lock().unwrap();
-        let mut pending_updates = self.pending_updates.lock().unwrap();
-
-        if !local_buffer.contains_key(name) {
-            log::info!(
-                "Can't rename the frame '{}', buffer doesn't contain it.",
-                name
-            );
-            return;
-        }
-
-        if global_buffer.contains_key(rename_to) {
-            log::info!(
-                "Can't rename the frame '{name}' to '{rename_to}', '{rename_to}' already exists.",
-                
-            );
-            return;
-        }
-
-        pending_updates.insert(
-            name.to_string(),
-            UpdateContext {
-                update_type: UpdateType::Rename,
-                transform: {
-                    let mut tf = TransformStamped::default();
-                    tf.child_frame_id = rename_to.to_string();
-                    tf
-                },
-            },
-        );
-
-        log::info!("Pending update: Rename transform with name '{name}' to '{rename_to}'.");
-
-    }
-
-    pub fn reparent_transform(&self, name: &str, reparent_to: &str) {
-        let local_buffer = self.local_buffer.lock().unwrap();
-        let global_buffer = self.global_buffer.lock().unwrap();
-        let mut pending_updates = self.pending_updates.lock().unwrap();
-
-        if !local_buffer.contains_key(name) {
-            log::info!(
-                "Can't reparent the frame '{}', buffer doesn't contain it.",
-                name
-            );
-            return;
-        }
-
-        if !global_buffer.contains_key(reparent_to) {
-            log::info!(
-                "Can't reparent the frame '{name}' to '{reparent_to}', reparent frame '{reparent_to}' doesn't exist.",
-                
-            );
-            return;
-        }
-
-        pending_updates.insert(
-            name.to_string(),
-            UpdateContext {
-                update_type: UpdateType::Reparent,
-                transform: {
-                    let mut tf = TransformStamped::default();
-                    tf.parent_frame_id = reparent_to.to_string();
-                    tf
-                },
-            },
-        );
-
-        log::info!("Pending update: Reparent transform with name '{name}' to '{reparent_to}'.");
-
     }
 
-    pub fn clone_transform(&self, name: &str, clone_name: &str) {
-        let global_buffer = self.global_buffer.lock().unwrap();
-        let mut pending_updates = self.pending_updates.lock().unwrap();
-
-        if !global_buffer.contains_key(name){
-            log::info!(
-                "Can't clone the frame '{}', it doesn't exist.",
-                name
-            );
-            return;
-        }
-
-        pending_updates.insert(
-            name.to_string(),
-            UpdateContext {
-                update_type: UpdateType::Clone,
-                transform: {
-                    let mut tf = TransformStamped::default();
-                    tf.child_frame_id = clone_name.to_string();
-                    tf
-                },
-            },
-        );
-
-        log::info!("Pending update: Clone transform with name '{name}' to '{clone_name}'.");
-
+    /// Sets whether lookups at a time past the newest sample of a frame hold that sample or fail.
+    pub fn with_extrapolation(mut self, allow_extrapolation: bool) -> Self {
+        self.space_tree = self.space_tree.with_extrapolation(allow_extrapolation);
+        self
     }
 
-    pub fn delete_all_transforms(&self) {
-        let mut pending_updates = self.pending_updates.lock().unwrap();
-        pending_updates.clear();
-
-        pending_updates.insert(
-            "delete_all".to_string(),
-            UpdateContext {
-                update_type: UpdateType::DeleteAll,
-                transform: TransformStamped::default(),
-            },
-        );
-
-        log::info!("Pending update: Delete all (local) transforms.");
+    /// Sets how many committed batches can be undone.
+    pub fn with_undo_depth(mut self, undo_depth: usize) -> Self {
+        self.space_tree = self.space_tree.with_undo_depth(undo_depth);
+        self
     }
 
-    pub fn lookup_transform(&self, parent_frame_id: &str, child_frame_id: &str) -> Option<TransformStamped> {
-        let buffer = self.global_buffer.lock().unwrap();
-        match get_tree_root(&buffer) {
-            Some(root) => {
-                lookup_transform_with_root(parent_frame_id, child_frame_id, &root, &self.global_buffer)
-            },
-            None => {
-                lookup_transform_with_root(parent_frame_id, child_frame_id, "world", &self.global_buffer)
-            }
-        }
-        
+    /// Sets the rotation encoding used by save_scenario and save_frames.
+    pub fn with_rotation_encoding(mut self, rotation_encoding: RotationEncoding) -> Self {
+        self.space_tree = self.space_tree.with_rotation_encoding(rotation_encoding);
+        self
     }
 
-    pub fn lookup_with_root(&self, parent_frame_id: &str, child_frame_id: &str, root_frame_id: &str) -> Option<TransformStamped> {
-        lookup_transform_with_root(parent_frame_id, child_frame_id, root_frame_id, &self.global_buffer)
+    /// Subscribes to sensor_msgs/JointState messages on the topic, and moves
+    /// and commits the joint frames on every message, see commit_joint_positions.
+    pub fn connect_joint_states(&self, node: &Arc<Mutex<r2r::Node>>, topic: &str) {
+        self.connect_joint_states_in(node, topic, None)
     }
 
-    pub fn get_local_transform_names(&self) -> Vec<String> {
-        let buffer = self.local_buffer.lock().unwrap();
-        buffer.keys().map(|k| k.to_owned()).collect::<Vec<String>>()
+    /// Same as connect_joint_states, but the joint names are resolved in the scope of one loaded URDF,
+    /// for robots that share joint names and publish their joint states on separate topics.
+    pub fn connect_joint_states_in(&self, node: &Arc<Mutex<r2r::Node>>, topic: &str, scope: Option<&str>) {
+        let joint_state_listener = node
+            .lock()
+            .unwrap()
+            .subscribe::<JointState>(topic, QosProfile::volatile(QosProfile::default()))
+            .expect("Failed to initialize joint_state_listener.");
+        let server = self.clone();
+        let scope = scope.map(|scope| scope.to_string());
+        tokio::task::spawn(async move {
+            match joint_state_listener_callback(joint_state_listener, &server, scope.as_deref()).await {
+                Ok(()) => (),
+                Err(e) => r2r::log_error!("r2r_transforms", "Joint state listener failed with: '{}'.", e),
+            };
+        });
     }
 
     pub fn get_global_transform_names(&self) -> Vec<String> {
@@ -383,112 +258,9 @@ impl RosSpaceTreeServer {
         buffer.keys().map(|k| k.to_owned()).collect::<Vec<String>>()
     }
 
-    /// Applies pending updates to the transform buffer.
-    pub fn apply_changes(&self) {
-        let mut local_buffer = self.local_buffer.lock().unwrap().clone();
-        let global_buffer = self.global_buffer.lock().unwrap().clone();
-        let mut pending_updates = self.pending_updates.lock().unwrap();
-
-        if pending_updates.is_empty() {
-            log::info!("No changes to apply");
-            return;
-        }
-
-        for (name, update_context) in pending_updates.iter() {
-            match update_context.update_type {
-                UpdateType::Add => {
-                    if name != &update_context.transform.child_frame_id {
-                        log::info!("Transform name '{name}' in buffer doesn't match the child_frame_id {}, they should be the same. Not added.", update_context.transform.child_frame_id);
-                    } else if let Some(_) = global_buffer.get(name) {
-                        log::info!("Transform '{}' already exists, not added.", name);
-                    } else {
-                        let transform = update_context.transform.clone();
-                        if check_would_produce_cycle(&transform, &global_buffer) {
-                            log::info!("Transform '{}' would produce cycle, not added.", name);
-                        } else {
-                            local_buffer.insert(name.to_string(), transform);
-                            log::info!("Inserted transform '{name}'.");
-                        }
-                    }
-                }
-                UpdateType::Move => {
-                    if let Some(transform) = local_buffer.get_mut(name) {
-                        transform.transform = update_context.transform.transform.clone();
-                        log::info!("Moved transform '{name}'.");
-                    } else {
-                        log::info!("Can't move transform '{}' because it doesn't exist.", name);
-                    }
-                }
-                UpdateType::Remove => {
-                    if let Some(_) = local_buffer.get(name) {
-                        local_buffer.remove(name);
-                        log::info!("Removed transform '{name}'.");
-                    } else {
-                        log::info!(
-                            "Can't remove transform '{}' because it doesn't exist.",
-                            name
-                        );
-                    }
-                }
-                UpdateType::Rename => {
-                    if let Some(transform) = local_buffer.clone().get(name) {
-                        let mut temp = transform.clone();
-                        temp.child_frame_id = update_context.transform.child_frame_id.clone();
-                        local_buffer.remove(name);
-                        local_buffer.insert(
-                            update_context.transform.child_frame_id.to_string(),
-                            temp.clone(),
-                        );
-                        log::info!("Renamed transform '{name}' to '{}'.", update_context.transform.child_frame_id);
-                    } else {
-                        log::info!(
-                            "Can't rename transform '{}' because it doesn't exist.",
-                            name
-                        );
-                    }
-                }
-                UpdateType::Reparent => {
-                    if let Some(transform) = local_buffer.get(name) {
-                        let mut temp = transform.clone();
-                        let old_parent = temp.parent_frame_id;
-                        temp.parent_frame_id = update_context.transform.parent_frame_id.clone();
-                        if check_would_produce_cycle(&temp, &global_buffer) {
-                            log::info!("Transform '{}' would produce cycle if reparented, no action taken.", name);
-                        } else {
-                            temp.parent_frame_id = update_context.transform.parent_frame_id.clone();
-                            local_buffer.insert(name.clone(), temp);
-                            log::info!("Reparented transform '{name}' from '{}' to '{}'.", old_parent, update_context.transform.parent_frame_id);
-                        }
-                    } else {
-                        log::info!(
-                            "Can't reparent transform '{}' because it doesn't exist.",
-                            name
-                        );
-                    }
-                }
-                UpdateType::Clone => {
-                    if let Some(transform) = global_buffer.get(name) {
-                        let mut new_transform = transform.clone();
-                        new_transform.child_frame_id =
-                            update_context.transform.child_frame_id.clone();
-                        local_buffer.insert(
-                            update_context.transform.child_frame_id.clone(),
-                            new_transform.clone(),
-                        );
-                        log::info!("Cloned transform '{name}' as '{}'.", new_transform.child_frame_id);
-                    } else {
-                        log::info!("Can't clone transform '{}' because it doesn't exist.", name);
-                    }
-                }
-                UpdateType::DeleteAll => {
-                    local_buffer.clear();
-                    log::info!("All transforms deleted from the (local) buffer.");
-                }
-            }
-        }
-
-        pending_updates.clear();
-        *self.local_buffer.lock().unwrap() = local_buffer;
+    /// Returns a copy of all the transforms seen on /tf and /tf_static.
+    pub fn get_global_transforms(&self) -> HashMap<String, TransformStamped> {
+        self.global_buffer.lock().unwrap().clone()
     }
 
     // This conditionally includes a method which implements r2r support
@@ -1,14 +1,21 @@
-use utils::lookup::get_frame_children;
-
 use crate::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 pub static MAX_TRANSFORM_CHAIN: u64 = 1000;
 
 // Check for cycles in the tree segment starting from this frame
 pub fn is_cyclic(frame: &str, buffer: &HashMap<String, TransformStamped>) -> bool {
+    is_cyclic_indexed(frame, buffer, &build_children_index(buffer))
+}
+
+// Same as is_cyclic, but walks down the tree through the children index
+pub fn is_cyclic_indexed(
+    frame: &str,
+    buffer: &HashMap<String, TransformStamped>,
+    children: &ChildrenIndex,
+) -> bool {
     let mut stack = vec![frame.to_string()];
-    let mut visited = vec![];
+    let mut visited = HashSet::new();
 
     loop {
         match stack.pop() {
@@ -16,11 +23,10 @@ pub fn is_cyclic(frame: &str, buffer: &HashMap<String, TransformStamped>) -> boo
                 if visited.contains(&current_frame) && buffer.contains_key(&current_frame) {
                     break true;
                 } else {
-                    visited.push(current_frame.clone());
-
-                    for child in get_frame_children(&current_frame, buffer) {
-                        stack.push(child.1.child_frame_id);
+                    if let Some(names) = children.get(&current_frame) {
+                        stack.extend(names.iter().cloned());
                     }
+                    visited.insert(current_frame);
                 }
             }
             None => break false,
@@ -30,14 +36,59 @@ pub fn is_cyclic(frame: &str, buffer: &HashMap<String, TransformStamped>) -> boo
 
 // Check for all cycles including all frames even if tree is segmented
 pub fn is_cyclic_all(frames: &HashMap<String, TransformStamped>) -> bool {
-    for (k, _) in frames {
-        if is_cyclic(k, frames) {
+    find_cyclic_frame(frames, &build_children_index(frames)).is_some()
+}
+
+// Find a frame that is part of a cycle. Every frame that can't be reached by walking down
+// from the roots is in a cycle or below one, so this takes one pass over the frames.
+pub fn find_cyclic_frame(
+    buffer: &HashMap<String, TransformStamped>,
+    children: &ChildrenIndex,
+) -> Option<String> {
+    let mut reached: HashSet<&str> = HashSet::new();
+    let mut stack: Vec<&str> = children
+        .keys()
+        .filter(|parent| !buffer.contains_key(*parent))
+        .map(|parent| parent.as_str())
+        .collect();
+    while let Some(frame) = stack.pop() {
+        for child in children.get(frame).into_iter().flatten() {
+            if reached.insert(child) {
+                stack.push(child);
+            }
+        }
+    }
+
+    // Go up from a frame that wasn't reached until a frame repeats, that one is in the cycle
+    let mut current = buffer.keys().find(|frame| !reached.contains(frame.as_str()))?.clone();
+    let mut visited = HashSet::new();
+    while visited.insert(current.clone()) {
+        current = buffer.get(&current)?.parent_frame_id.clone();
+    }
+    Some(current)
+}
+
+// Check if hanging the frame under the parent would close a cycle, by walking up from the parent until
+// the frame is reached or the chain leaves the frames. The frames are looked up in the buffer first
+// and then in the external frames. Chains longer than MAX_TRANSFORM_CHAIN count as cycles.
+pub fn would_close_cycle(
+    name: &str,
+    parent_frame_id: &str,
+    buffer: &HashMap<String, TransformStamped>,
+    external: &HashMap<String, TransformStamped>,
+) -> bool {
+    let mut current = parent_frame_id;
+    let mut length = 0;
+    loop {
+        if current == name || length > MAX_TRANSFORM_CHAIN {
             return true;
-        } else {
-            continue;
         }
+        match buffer.get(current).or_else(|| external.get(current)) {
+            Some(transform) => current = &transform.parent_frame_id,
+            None => return false,
+        }
+        length += 1;
     }
-    false
 }
 
 // check if adding the frame to the tree would produce a cycle
@@ -55,16 +106,16 @@ mod tests {
 
     use nalgebra::Isometry3;
     use serde_json::Value;
-    use utils::cycles::{check_would_produce_cycle, is_cyclic, is_cyclic_all};
+    use utils::cycles::{check_would_produce_cycle, is_cyclic, is_cyclic_all, would_close_cycle};
     use std::collections::HashMap;
-    use tokio::time::Instant;
+    use std::time::SystemTime;
 
     use crate::*;
 
     fn dummy_1_frame() -> TransformStamped {
         TransformStamped {
             active: true,
-            time_stamp: Instant::now(),
+            time_stamp: SystemTime::now(),
             parent_frame_id: "world".to_string(),
             child_frame_id: "dummy_1".to_string(),
             transform: Isometry3::default(),
@@ -75,7 +126,7 @@ mod tests {
     fn dummy_2_frame() -> TransformStamped {
         TransformStamped {
             active: true,
-            time_stamp: Instant::now(),
+            time_stamp: SystemTime::now(),
             parent_frame_id: "dummy_1".to_string(),
             child_frame_id: "dummy_2".to_string(),
             transform: Isometry3::default(),
@@ -86,7 +137,7 @@ mod tests {
     fn dummy_3_frame() -> TransformStamped {
         TransformStamped {
             active: true,
-            time_stamp: Instant::now(),
+            time_stamp: SystemTime::now(),
             parent_frame_id: "dummy_2".to_string(),
             child_frame_id: "dummy_3".to_string(),
             transform: Isometry3::default(),
@@ -131,7 +182,7 @@ mod tests {
             "dummy_1".to_string(),
             TransformStamped {
                 active: true,
-                time_stamp: Instant::now(),
+                time_stamp: SystemTime::now(),
                 parent_frame_id: "dummy_2".to_string(),
                 child_frame_id: "dummy_1".to_string(),
                 transform: Isometry3::default(),
@@ -179,7 +230,7 @@ mod tests {
             "dummy_1".to_string(),
             TransformStamped {
                 active: true,
-                time_stamp: Instant::now(),
+                time_stamp: SystemTime::now(),
                 parent_frame_id: "dummy_3".to_string(),
                 child_frame_id: "dummy_1".to_string(),
                 transform: Isometry3::default(),
@@ -224,7 +275,7 @@ mod tests {
             "dummy_5".to_string(),
             TransformStamped {
                 active: true,
-                time_stamp: Instant::now(),
+                time_stamp: SystemTime::now(),
                 parent_frame_id: "dummy_4".to_string(),
                 child_frame_id: "dummy_5".to_string(),
                 transform: Isometry3::default(),
@@ -236,7 +287,7 @@ mod tests {
             "dummy_6".to_string(),
             TransformStamped {
                 active: true,
-                time_stamp: Instant::now(),
+                time_stamp: SystemTime::now(),
                 parent_frame_id: "dummy_5".to_string(),
                 child_frame_id: "dummy_6".to_string(),
                 transform: Isometry3::default(),
@@ -257,7 +308,7 @@ mod tests {
             "dummy_4".to_string(),
             TransformStamped {
                 active: true,
-                time_stamp: Instant::now(),
+                time_stamp: SystemTime::now(),
                 parent_frame_id: "dummy_6".to_string(),
                 child_frame_id: "dummy_4".to_string(),
                 transform: Isometry3::default(),
@@ -278,7 +329,7 @@ mod tests {
             "dummy_4".to_string(),
             TransformStamped {
                 active: true,
-                time_stamp: Instant::now(),
+                time_stamp: SystemTime::now(),
                 parent_frame_id: "world".to_string(),
                 child_frame_id: "dummy_4".to_string(),
                 transform: Isometry3::default(),
@@ -321,7 +372,7 @@ mod tests {
         assert_eq!(check_would_produce_cycle(
             &TransformStamped {
                 active: true,
-                time_stamp: Instant::now(),
+                time_stamp: SystemTime::now(),
                 parent_frame_id: "dummy_4".to_string(),
                 child_frame_id: "dummy_1".to_string(),
                 transform: Isometry3::default(),
@@ -333,7 +384,7 @@ mod tests {
         assert_eq!(check_would_produce_cycle(
             &TransformStamped {
                 active: true,
-                time_stamp: Instant::now(),
+                time_stamp: SystemTime::now(),
                 parent_frame_id: "dummy_3".to_string(),
                 child_frame_id: "dummy_1".to_string(),
                 transform: Isometry3::default(),
@@ -343,5 +394,21 @@ mod tests {
         );
     }
 
-
+    #[test]
+    fn test_would_close_cycle() {
+        let mut buffer = HashMap::new();
+        buffer.insert("dummy_1".to_string(), dummy_1_frame());
+        buffer.insert("dummy_2".to_string(), dummy_2_frame());
+        let mut dummy_3 = dummy_2_frame();
+        dummy_3.parent_frame_id = "dummy_2".to_string();
+        dummy_3.child_frame_id = "dummy_3".to_string();
+        let external = HashMap::from([("dummy_3".to_string(), dummy_3)]);
+
+        assert!(!would_close_cycle("dummy_4", "dummy_2", &buffer, &external));
+        assert!(would_close_cycle("dummy_1", "dummy_2", &buffer, &external));
+        assert!(would_close_cycle("dummy_1", "dummy_1", &buffer, &external));
+        // The chain goes on through the external frames
+        assert!(would_close_cycle("dummy_1", "dummy_3", &buffer, &external));
+        assert!(!would_close_cycle("dummy_1", "world", &buffer, &external));
+    }
 }
@@ -0,0 +1,121 @@
+use crate::*;
+use std::collections::HashMap;
+
+// The events of the updates that were committed, in the order they were applied.
+pub fn events_from_report(report: &ApplyReport) -> Vec<SpaceTreeEvent> {
+    report
+        .applied()
+        .into_iter()
+        .map(|update| {
+            let target = update.target.clone().unwrap_or_default();
+            match update.update_type {
+                UpdateType::Add => SpaceTreeEvent::FrameAdded(update.name.clone()),
+                UpdateType::Move => SpaceTreeEvent::FrameMoved(update.name.clone()),
+                UpdateType::Replace => SpaceTreeEvent::FrameReplaced(update.name.clone()),
+                UpdateType::Remove => SpaceTreeEvent::FrameRemoved(update.name.clone()),
+                UpdateType::Rename => SpaceTreeEvent::FrameRenamed {
+                    name: update.name.clone(),
+                    rename_to: target,
+                },
+                UpdateType::Reparent(_) => SpaceTreeEvent::FrameReparented {
+                    name: update.name.clone(),
+                    parent_frame_id: target,
+                },
+                UpdateType::Clone => SpaceTreeEvent::FrameAdded(target),
+                UpdateType::DeleteAll => SpaceTreeEvent::AllDeleted,
+            }
+        })
+        .collect()
+}
+
+// The events that take the buffer from one side of the diff to the other,
+// used when an undo or redo restores frames instead of applying updates.
+pub fn events_from_diff(
+    from: &HashMap<String, Option<TransformStamped>>,
+    to: &HashMap<String, Option<TransformStamped>>,
+) -> Vec<SpaceTreeEvent> {
+    let mut names: Vec<&String> = to.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .filter_map(|name| match (from.get(name).cloned().flatten(), to[name].as_ref()) {
+            (None, Some(_)) => Some(SpaceTreeEvent::FrameAdded(name.clone())),
+            (Some(_), None) => Some(SpaceTreeEvent::FrameRemoved(name.clone())),
+            (Some(old), Some(new)) if old.parent_frame_id != new.parent_frame_id => {
+                Some(SpaceTreeEvent::FrameReparented {
+                    name: name.clone(),
+                    parent_frame_id: new.parent_frame_id.clone(),
+                })
+            }
+            (Some(_), Some(_)) => Some(SpaceTreeEvent::FrameMoved(name.clone())),
+            (None, None) => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+
+    use crate::*;
+
+    fn update(name: &str, update_type: UpdateType, target: Option<&str>, applied: bool) -> UpdateReport {
+        UpdateReport {
+            name: name.to_string(),
+            update_type,
+            target: target.map(|t| t.to_string()),
+            applied,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_events_from_report() {
+        let report = ApplyReport {
+            updates: vec![
+                update("a", UpdateType::Add, None, true),
+                update("b", UpdateType::Add, None, false),
+                update("a", UpdateType::Rename, Some("c"), true),
+                update("c", UpdateType::Clone, Some("d"), true),
+                update("d", UpdateType::Reparent(ReparentMode::KeepWorldPose), Some("c"), true),
+            ],
+        };
+        assert_eq!(
+            events_from_report(&report),
+            vec![
+                SpaceTreeEvent::FrameAdded("a".to_string()),
+                SpaceTreeEvent::FrameRenamed { name: "a".to_string(), rename_to: "c".to_string() },
+                SpaceTreeEvent::FrameAdded("d".to_string()),
+                SpaceTreeEvent::FrameReparented { name: "d".to_string(), parent_frame_id: "c".to_string() },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_events_from_diff() {
+        let mut moved = TransformStamped::default();
+        moved.parent_frame_id = "world".to_string();
+        let mut reparented = moved.clone();
+        reparented.parent_frame_id = "table".to_string();
+
+        let before = HashMap::from([
+            ("a".to_string(), None),
+            ("b".to_string(), Some(moved.clone())),
+            ("c".to_string(), Some(moved.clone())),
+        ]);
+        let after = HashMap::from([
+            ("a".to_string(), Some(moved.clone())),
+            ("b".to_string(), None),
+            ("c".to_string(), Some(reparented)),
+        ]);
+        assert_eq!(
+            events_from_diff(&before, &after),
+            vec![
+                SpaceTreeEvent::FrameAdded("a".to_string()),
+                SpaceTreeEvent::FrameRemoved("b".to_string()),
+                SpaceTreeEvent::FrameReparented { name: "c".to_string(), parent_frame_id: "table".to_string() },
+            ]
+        );
+    }
+}
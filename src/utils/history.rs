@@ -0,0 +1,219 @@
+use crate::*;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use std::time::SystemTime;
+
+// Add a sample to the frame's history, keeping the samples sorted by time stamp
+// and dropping the ones that fall out of the cache time window.
+pub fn record_transform_sample(
+    history: &mut HashMap<String, VecDeque<TransformStamped>>,
+    transform: &TransformStamped,
+    cache_time: Duration,
+) {
+    let samples = history
+        .entry(transform.child_frame_id.clone())
+        .or_default();
+
+    let position = samples
+        .iter()
+        .rposition(|s| s.time_stamp <= transform.time_stamp)
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    samples.insert(position, transform.clone());
+
+    prune_samples(samples, cache_time);
+}
+
+// Drop samples older than the cache time, but keep the last sample before the
+// window start so that lookups at the very edge of the window still succeed.
+pub fn prune_samples(samples: &mut VecDeque<TransformStamped>, cache_time: Duration) {
+    let newest = match samples.back() {
+        Some(sample) => sample.time_stamp,
+        None => return,
+    };
+    let cutoff = match newest.checked_sub(cache_time) {
+        Some(cutoff) => cutoff,
+        None => return,
+    };
+    while samples.len() > 1 && samples[1].time_stamp <= cutoff {
+        samples.pop_front();
+    }
+}
+
+// Record a sample for every frame that was added or changed in the new buffer and forget
+// the history of the frames that were removed. External frames keep the history that
+// whoever owns them records, even if one of ours with the same name was removed.
+pub fn record_buffer_changes(
+    history: &mut HashMap<String, VecDeque<TransformStamped>>,
+    old_buffer: &HashMap<String, TransformStamped>,
+    new_buffer: &HashMap<String, TransformStamped>,
+    external: &HashMap<String, TransformStamped>,
+    cache_time: Duration,
+) {
+    for (name, transform) in new_buffer {
+        if old_buffer.get(name) != Some(transform) {
+            record_transform_sample(history, transform, cache_time);
+        }
+    }
+    for name in old_buffer.keys() {
+        if !new_buffer.contains_key(name) && !external.contains_key(name) {
+            history.remove(name);
+        }
+    }
+}
+
+// The frame's transform at the given time, interpolated between the two samples around it.
+// Static frames don't change over time, so their latest sample is always valid.
+// Past the newest sample the latest one is held, unless extrapolation is not allowed.
+pub fn sample_at(
+    frame_id: &str,
+    samples: &VecDeque<TransformStamped>,
+    time: SystemTime,
+    allow_extrapolation: bool,
+) -> Result<TransformStamped, TransformError> {
+    let (oldest, newest) = match (samples.front(), samples.back()) {
+        (Some(oldest), Some(newest)) => (oldest, newest),
+        _ => return Err(TransformError::NoSamples(frame_id.to_string())),
+    };
+
+    if !newest.active {
+        return Ok(newest.clone());
+    }
+
+    if time < oldest.time_stamp {
+        return Err(TransformError::ExtrapolationIntoPast {
+            frame_id: frame_id.to_string(),
+            by: oldest.time_stamp.duration_since(time).unwrap_or_default(),
+        });
+    }
+
+    if time >= newest.time_stamp {
+        if time > newest.time_stamp && !allow_extrapolation {
+            return Err(TransformError::ExtrapolationIntoFuture {
+                frame_id: frame_id.to_string(),
+                by: time.duration_since(newest.time_stamp).unwrap_or_default(),
+            });
+        }
+        return Ok(newest.clone());
+    }
+
+    let after = samples.partition_point(|s| s.time_stamp <= time);
+    Ok(interpolate_transforms(&samples[after - 1], &samples[after], time))
+}
+
+// Reconstruct the buffer as it was at the given time. Frames that can't be
+// resolved at that time carry the reason instead of the transform.
+pub fn buffer_at_time(
+    buffer: &HashMap<String, TransformStamped>,
+    history: &HashMap<String, VecDeque<TransformStamped>>,
+    time: SystemTime,
+    allow_extrapolation: bool,
+) -> HashMap<String, Result<TransformStamped, TransformError>> {
+    buffer
+        .iter()
+        .map(|(name, current)| {
+            let sample = match history.get(name) {
+                Some(samples) => sample_at(name, samples, time, allow_extrapolation),
+                None => sample_at(name, &VecDeque::from([current.clone()]), time, allow_extrapolation),
+            };
+            (name.clone(), sample)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use nalgebra::Isometry3;
+    use serde_json::Value;
+    use std::collections::{HashMap, VecDeque};
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    use crate::*;
+
+    fn sample(child: &str, x: f64, time_stamp: SystemTime) -> TransformStamped {
+        TransformStamped {
+            active: true,
+            time_stamp,
+            parent_frame_id: "world".to_string(),
+            child_frame_id: child.to_string(),
+            transform: Isometry3::translation(x, 0.0, 0.0),
+            metadata: Value::default()
+        }
+    }
+
+    #[test]
+    fn test_samples_are_sorted_and_pruned() {
+        let start = SystemTime::now();
+        let mut history = HashMap::<String, VecDeque<TransformStamped>>::new();
+        let cache_time = Duration::from_millis(100);
+
+        record_transform_sample(&mut history, &sample("a", 2.0, start + Duration::from_millis(20)), cache_time);
+        record_transform_sample(&mut history, &sample("a", 1.0, start + Duration::from_millis(10)), cache_time);
+        assert_eq!(history["a"].len(), 2);
+        assert_eq!(history["a"][0].transform.translation.x, 1.0);
+
+        record_transform_sample(&mut history, &sample("a", 3.0, start + Duration::from_millis(500)), cache_time);
+
+        // The sample at 20 ms is the last one before the window, so it stays.
+        assert_eq!(history["a"].len(), 2);
+        assert_eq!(history["a"][0].transform.translation.x, 2.0);
+    }
+
+    #[test]
+    fn test_sample_at() {
+        let start = SystemTime::now();
+        let mut samples = VecDeque::new();
+        samples.push_back(sample("a", 1.0, start + Duration::from_millis(10)));
+        samples.push_back(sample("a", 2.0, start + Duration::from_millis(20)));
+
+        assert_eq!(
+            sample_at("a", &samples, start, true),
+            Err(TransformError::ExtrapolationIntoPast { frame_id: "a".to_string(), by: Duration::from_millis(10) })
+        );
+        assert_eq!(sample_at("a", &samples, start + Duration::from_millis(10), true).unwrap().transform.translation.x, 1.0);
+        assert_eq!(sample_at("a", &samples, start + Duration::from_millis(15), true).unwrap().transform.translation.x, 1.5);
+        assert_eq!(sample_at("a", &samples, start + Duration::from_millis(25), true).unwrap().transform.translation.x, 2.0);
+        assert_eq!(
+            sample_at("a", &samples, start + Duration::from_millis(25), false),
+            Err(TransformError::ExtrapolationIntoFuture { frame_id: "a".to_string(), by: Duration::from_millis(5) })
+        );
+
+        samples.back_mut().unwrap().active = false;
+        assert_eq!(sample_at("a", &samples, start, false).unwrap().transform.translation.x, 2.0);
+    }
+
+    #[test]
+    fn test_buffer_at_time() {
+        let start = SystemTime::now();
+        let cache_time = Duration::from_millis(1000);
+        let mut history = HashMap::<String, VecDeque<TransformStamped>>::new();
+
+        let old = HashMap::new();
+        let first = HashMap::from([("a".to_string(), sample("a", 1.0, start))]);
+        record_buffer_changes(&mut history, &old, &first, &HashMap::new(), cache_time);
+
+        let second = HashMap::from([
+            ("a".to_string(), sample("a", 2.0, start + Duration::from_millis(50))),
+            ("b".to_string(), sample("b", 5.0, start + Duration::from_millis(50))),
+        ]);
+        record_buffer_changes(&mut history, &first, &second, &HashMap::new(), cache_time);
+
+        let past = buffer_at_time(&second, &history, start + Duration::from_millis(10), true);
+        assert!(past["b"].is_err());
+        assert!((past["a"].as_ref().unwrap().transform.translation.x - 1.2).abs() < 1e-9);
+
+        let now = buffer_at_time(&second, &history, start + Duration::from_millis(60), true);
+        assert_eq!(now["a"].as_ref().unwrap().transform.translation.x, 2.0);
+        assert_eq!(now["b"].as_ref().unwrap().transform.translation.x, 5.0);
+
+        record_buffer_changes(&mut history, &second, &first, &HashMap::new(), cache_time);
+        assert!(!history.contains_key("b"));
+
+        // An external frame with the name of a removed one keeps its history
+        let external = HashMap::from([("a".to_string(), sample("a", 3.0, start))]);
+        record_buffer_changes(&mut history, &first, &HashMap::new(), &external, cache_time);
+        assert!(history.contains_key("a"));
+    }
+}
@@ -0,0 +1,106 @@
+use crate::*;
+use std::collections::HashMap;
+
+// The names of the children of every parent frame, sorted, so that walking down
+// the tree doesn't have to scan the whole buffer for every frame on the way.
+pub type ChildrenIndex = HashMap<String, Vec<String>>;
+
+pub fn build_children_index(buffer: &HashMap<String, TransformStamped>) -> ChildrenIndex {
+    let mut children = ChildrenIndex::new();
+    for (name, transform) in buffer {
+        children
+            .entry(transform.parent_frame_id.clone())
+            .or_default()
+            .push(name.clone());
+    }
+    children.values_mut().for_each(|names| names.sort_unstable());
+    children
+}
+
+// Keep the index in step with a frame that changed from before to after, None if it didn't or doesn't exist
+pub fn update_children_index(
+    children: &mut ChildrenIndex,
+    name: &str,
+    before: Option<&TransformStamped>,
+    after: Option<&TransformStamped>,
+) {
+    if let Some(before) = before {
+        if let Some(names) = children.get_mut(&before.parent_frame_id) {
+            if let Ok(position) = names.binary_search_by(|n| n.as_str().cmp(name)) {
+                names.remove(position);
+            }
+            if names.is_empty() {
+                children.remove(&before.parent_frame_id);
+            }
+        }
+    }
+    if let Some(after) = after {
+        let names = children.entry(after.parent_frame_id.clone()).or_default();
+        if let Err(position) = names.binary_search_by(|n| n.as_str().cmp(name)) {
+            names.insert(position, name.to_string());
+        }
+    }
+}
+
+// The index of the committed frames together with the external frames they can refer to
+pub fn merge_children_index(
+    children: &ChildrenIndex,
+    buffer: &HashMap<String, TransformStamped>,
+    external: &HashMap<String, TransformStamped>,
+) -> ChildrenIndex {
+    let mut merged = children.clone();
+    for (name, transform) in external {
+        if !buffer.contains_key(name) {
+            update_children_index(&mut merged, name, None, Some(transform));
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+
+    use crate::*;
+
+    fn frame(parent: &str, child: &str) -> TransformStamped {
+        let mut transform = TransformStamped::default();
+        transform.parent_frame_id = parent.to_string();
+        transform.child_frame_id = child.to_string();
+        transform
+    }
+
+    #[test]
+    fn test_update_children_index() {
+        let mut buffer: HashMap<String, TransformStamped> = [("c", "world"), ("a", "world"), ("b", "a")]
+            .iter()
+            .map(|(child, parent)| (child.to_string(), frame(parent, child)))
+            .collect();
+        let mut children = build_children_index(&buffer);
+        assert_eq!(children["world"], vec!["a", "c"]);
+        assert_eq!(children["a"], vec!["b"]);
+
+        // Reparent c under a, remove b and add d
+        let changes = [
+            ("c", Some(frame("a", "c"))),
+            ("b", None),
+            ("d", Some(frame("world", "d"))),
+        ];
+        for (name, after) in changes {
+            let before = buffer.get(name).cloned();
+            update_children_index(&mut children, name, before.as_ref(), after.as_ref());
+            match after {
+                Some(after) => buffer.insert(name.to_string(), after),
+                None => buffer.remove(name),
+            };
+        }
+        assert_eq!(children, build_children_index(&buffer));
+        assert_eq!(children["world"], vec!["a", "d"]);
+        assert_eq!(children["a"], vec!["c"]);
+
+        let external = HashMap::from([("e".to_string(), frame("d", "e"))]);
+        let merged = merge_children_index(&children, &buffer, &external);
+        assert_eq!(merged["d"], vec!["e"]);
+    }
+}
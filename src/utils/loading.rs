@@ -2,40 +2,14 @@ use serde_json::Value;
 use std::{
     collections::HashMap,
     fs::{self, File},
-    io::BufReader,
+    io::{BufReader, BufWriter},
+    path::Path,
 };
-use tokio::time::Instant;
+use std::time::SystemTime;
 
 use crate::*;
-use std::error::Error;
-use std::fmt;
 
-#[derive(Debug, Clone)]
-pub struct ErrorMsg {
-    info: String,
-}
-
-impl ErrorMsg {
-    pub fn new(info: &str) -> ErrorMsg {
-        ErrorMsg {
-            info: info.to_string(),
-        }
-    }
-}
-
-impl fmt::Display for ErrorMsg {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.info)
-    }
-}
-
-impl Error for ErrorMsg {
-    fn description(&self) -> &str {
-        &self.info
-    }
-}
-
-pub fn list_frames_in_dir(path: &str) -> Result<Vec<String>, Box<dyn std::error::Error + Send>> {
+pub fn list_frames_in_dir(path: &str) -> Result<Vec<String>, TransformError> {
     let mut scenario = vec![];
     match fs::read_dir(path) {
         Ok(dir) => dir.for_each(|file| match file {
@@ -53,11 +27,10 @@ pub fn list_frames_in_dir(path: &str) -> Result<Vec<String>, Box<dyn std::error:
                 e
             );
             log::warn!(target: "r2r_transforms", "Empty scenario is loaded.");
-            return Err(Box::new(ErrorMsg::new(&format!(
-                "Reading the scenario directory failed with: '{}'. 
-                    Empty scenario is loaded.",
-                e
-            ))));
+            return Err(TransformError::Io(format!(
+                "Reading the scenario directory '{}' failed with: '{}'.",
+                path, e
+            )));
         }
     }
     Ok(scenario)
@@ -67,137 +40,517 @@ pub fn load_new_scenario(scenario: &Vec<String>) -> HashMap<String, TransformSta
     let mut transforms_stamped = HashMap::new();
 
     for path in scenario {
-        let json = match load_json_from_file(path) {
-            Some(json) => json,
-            None => continue,
-        };
+        match load_frame_from_file(path) {
+            Ok(Some(frame)) => {
+                transforms_stamped.insert(frame.child_frame_id.clone(), frame);
+            }
+            Ok(None) => continue,
+            Err(e) => {
+                log::warn!(target: "r2r_transforms", "{}", e);
+                continue;
+            }
+        }
+    }
 
-        let child_frame_id = match extract_string_field(&json, "child_frame_id") {
-            Some(id) => id,
-            None => continue,
-        };
+    transforms_stamped
+}
 
-        let parent_frame_id = match extract_string_field(&json, "parent_frame_id") {
-            Some(id) => id,
-            None => continue,
-        };
+// Load a single frame file. Frames with enable_transform set to false are skipped and give None.
+pub fn load_frame_from_file(path: &str) -> Result<Option<TransformStamped>, TransformError> {
+    let json = load_json_from_file(path)?;
+    frame_from_json(path, &json)
+}
 
-        let transform = match extract_transform(&json) {
-            Some(transform) => transform,
-            None => continue,
-        };
+// Build a frame from its JSON description, the source is only used in error messages.
+pub fn frame_from_json(path: &str, json: &Value) -> Result<Option<TransformStamped>, TransformError> {
+    let child_frame_id = extract_string_field(path, json, "child_frame_id")?;
+    let parent_frame_id = extract_string_field(path, json, "parent_frame_id")?;
+    let transform = json_transform_to_isometry(extract_transform(path, json)?).map_err(|e| {
+        TransformError::Parse(format!("Invalid 'transform' field in '{}': '{}'.", path, e))
+    })?;
 
-        let metadata = json["metadata"].clone();
+    let metadata = json["metadata"].clone();
 
-        let active = if let Some(Value::Bool(val)) = metadata.get("active_transform") {
-            // println!("active_transform: {}", val);
-            *val
-        } else {
-            println!("active_transform not found or not a bool. Defaulting to true.");
-            true
-        };
+    let active = if let Some(Value::Bool(val)) = metadata.get("active_transform") {
+        // println!("active_transform: {}", val);
+        *val
+    } else {
+        log::debug!(target: "r2r_transforms", "'{}': active_transform not found or not a bool. Defaulting to true.", path);
+        true
+    };
 
-        let enable_transform = if let Some(Value::Bool(val)) = metadata.get("enable_transform") {
-            // println!("enable_transform: {}", val);
-            *val
-        } else {
-            println!("enable_transform not found or not a bool. Defaulting to true.");
-            true
-        };
+    let enable_transform = if let Some(Value::Bool(val)) = metadata.get("enable_transform") {
+        // println!("enable_transform: {}", val);
+        *val
+    } else {
+        log::debug!(target: "r2r_transforms", "'{}': enable_transform not found or not a bool. Defaulting to true.", path);
+        true
+    };
 
-        if enable_transform {
-            transforms_stamped.insert(
-                child_frame_id.clone(),
-                TransformStamped {
-                    active,
-                    time_stamp: Instant::now(),
-                    child_frame_id,
-                    parent_frame_id,
-                    transform: json_transform_to_isometry(transform),
-                    metadata,
-                },
-            );
+    if !enable_transform {
+        return Ok(None);
+    }
+
+    Ok(Some(TransformStamped {
+        active,
+        time_stamp: SystemTime::now(),
+        child_frame_id,
+        parent_frame_id,
+        transform,
+        metadata,
+    }))
+}
+
+// Load a whole scenario from a single JSON or YAML file, chosen by the file extension.
+// The file holds either a list of frames, or a map from frame names to frames,
+// in which case child_frame_id can be left out.
+pub fn load_scenario_file(path: &str) -> Result<HashMap<String, TransformStamped>, TransformError> {
+    let mut transforms_stamped = HashMap::new();
+    for (source, entry) in scenario_file_entries(path)? {
+        match frame_from_json(&source, &entry) {
+            Ok(Some(frame)) => {
+                transforms_stamped.insert(frame.child_frame_id.clone(), frame);
+            }
+            Ok(None) => continue,
+            Err(e) => log::warn!(target: "r2r_transforms", "{}", e),
         }
     }
+    Ok(transforms_stamped)
+}
+
+// The frame descriptions in a single scenario file, each with the place it came from
+fn scenario_file_entries(path: &str) -> Result<Vec<(String, Value)>, TransformError> {
+    let document = if is_yaml(path) {
+        let file = File::open(path).map_err(|e| {
+            TransformError::Io(format!("Opening yaml file '{}' failed with: '{}'.", path, e))
+        })?;
+        serde_yaml::from_reader::<_, Value>(BufReader::new(file)).map_err(|e| {
+            TransformError::Parse(format!("Deserialization of '{}' failed with: '{}'.", path, e))
+        })?
+    } else {
+        load_json_from_file(path)?
+    };
+
+    match document {
+        Value::Array(frames) => Ok(frames
+            .into_iter()
+            .enumerate()
+            .map(|(index, frame)| (format!("{}[{}]", path, index), frame))
+            .collect()),
+        Value::Object(frames) => Ok(frames
+            .into_iter()
+            .map(|(name, mut frame)| {
+                if let Some(object) = frame.as_object_mut() {
+                    object.entry("child_frame_id").or_insert(Value::String(name.clone()));
+                }
+                (format!("{}[{}]", path, name), frame)
+            })
+            .collect()),
+        _ => Err(TransformError::Parse(format!(
+            "The scenario file '{}' should contain a list or a map of frames.",
+            path
+        ))),
+    }
+}
 
-    transforms_stamped
+// Load the scenario from a directory of frame files, or from a single scenario file.
+pub fn load_scenario_from_path(path: &str) -> Result<HashMap<String, TransformStamped>, TransformError> {
+    if Path::new(path).is_file() {
+        load_scenario_file(path)
+    } else {
+        Ok(load_new_scenario(&list_frames_in_dir(path)?))
+    }
 }
 
-fn load_json_from_file(path: &str) -> Option<Value> {
-    match File::open(path) {
-        Ok(file) => {
-            let reader = BufReader::new(file);
-            match serde_json::from_reader(reader) {
-                Ok(json) => Some(json),
+// Metadata keys that the loaders, broadcasters and visualizers know about
+pub static KNOWN_METADATA_KEYS: [&str; 13] = [
+    "enable_transform",
+    "active_transform",
+    "active",
+    "mesh",
+    "enable_mesh",
+    "scale_x",
+    "scale_y",
+    "scale_z",
+    "color_a",
+    "color_r",
+    "color_g",
+    "color_b",
+    "joint",
+];
+
+// Load the scenario from a directory or a single file like load_scenario_from_path, but validate
+// every frame first and fail with all diagnostics if any of them is an error.
+// Otherwise the frames are returned together with the warnings, which are also logged.
+pub fn load_scenario_strict(
+    path: &str,
+) -> Result<(HashMap<String, TransformStamped>, Vec<FrameDiagnostic>), TransformError> {
+    let mut diagnostics = vec![];
+    let entries = if Path::new(path).is_file() {
+        scenario_file_entries(path)?
+    } else {
+        let mut files = list_frames_in_dir(path)?;
+        files.sort();
+        files
+            .into_iter()
+            .filter_map(|file| match load_json_from_file(&file) {
+                Ok(json) => Some((file, json)),
                 Err(e) => {
-                    log::warn!(target: "r2r_transforms",
-                        concat!(
-                            "Deserialization failed with: '{}'. ",
-                            "The JSON file may be malformed or contain ",
-                            "unexpected data."
-                        ),
-                        e
-                    );
+                    diagnostics.push(FrameDiagnostic::error(&file, "", &e.to_string()));
                     None
                 }
+            })
+            .collect()
+    };
+
+    let mut defined_in: HashMap<String, String> = HashMap::new();
+    let mut frames = HashMap::new();
+    for (source, json) in &entries {
+        let found = validate_frame_json(source, json);
+        let valid = found.iter().all(|d| d.severity == Severity::Warning);
+        diagnostics.extend(found);
+        if !valid {
+            continue;
+        }
+
+        let child_frame_id = json["child_frame_id"].as_str().unwrap_or_default().to_string();
+        if let Some(first) = defined_in.get(&child_frame_id) {
+            diagnostics.push(FrameDiagnostic::error(
+                source,
+                "child_frame_id",
+                &format!("'{}' is already defined in '{}'.", child_frame_id, first),
+            ));
+            continue;
+        }
+        defined_in.insert(child_frame_id, source.clone());
+
+        if let Ok(Some(frame)) = frame_from_json(source, json) {
+            frames.insert(frame.child_frame_id.clone(), frame);
+        }
+    }
+
+    if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        return Err(TransformError::InvalidScenario(diagnostics));
+    }
+    diagnostics.iter().for_each(|d| log::warn!(target: "r2r_transforms", "{}", d));
+    Ok((frames, diagnostics))
+}
+
+// Check a frame description against the scenario schema:
+// - "child_frame_id" and "parent_frame_id" are required non-empty strings that differ,
+// - "transform" requires "translation" with finite "x", "y", "z" and a finite "rotation" that is
+//   a unit quaternion, roll-pitch-yaw, axis-angle with a non-zero axis or a proper rotation matrix,
+// - "metadata" is an optional object, "enable_transform", "active_transform", "active" and
+//   "enable_mesh" in it have to be booleans. Unknown keys only give warnings.
+pub fn validate_frame_json(source: &str, json: &Value) -> Vec<FrameDiagnostic> {
+    let mut diagnostics = vec![];
+    let object = match json.as_object() {
+        Some(object) => object,
+        None => return vec![FrameDiagnostic::error(source, "", "A frame should be an object.")],
+    };
+
+    for field in ["child_frame_id", "parent_frame_id"] {
+        match object.get(field).and_then(|v| v.as_str()) {
+            Some("") => diagnostics.push(FrameDiagnostic::error(source, field, "Should not be empty.")),
+            Some(_) => (),
+            None => diagnostics.push(FrameDiagnostic::error(source, field, "Missing or not a string.")),
+        }
+    }
+    if object.get("child_frame_id").is_some() && object.get("child_frame_id") == object.get("parent_frame_id") {
+        diagnostics.push(FrameDiagnostic::error(source, "parent_frame_id", "A frame can't be its own parent."));
+    }
+
+    let mut number = |field: String, value: &Value| -> Option<f64> {
+        match value.as_f64() {
+            Some(number) if number.is_finite() => Some(number),
+            _ => {
+                diagnostics.push(FrameDiagnostic::error(source, &field, "Missing or not a finite number."));
+                None
+            }
+        }
+    };
+    let translation = &json["transform"]["translation"];
+    ["x", "y", "z"].iter().for_each(|axis| {
+        number(format!("transform.translation.{}", axis), &translation[*axis]);
+    });
+    let rotation = &json["transform"]["rotation"];
+    match serde_json::from_value::<JsonRotation>(rotation.clone()) {
+        Err(_) => diagnostics.push(FrameDiagnostic::error(
+            source,
+            "transform.rotation",
+            "Missing or not a quaternion, rpy, axis-angle or matrix rotation.",
+        )),
+        Ok(rotation) => diagnostics.extend(rotation_diagnostic(source, &rotation)),
+    }
+
+    match object.get("metadata") {
+        None | Some(Value::Null) => (),
+        Some(Value::Object(metadata)) => {
+            for (key, value) in metadata {
+                let field = format!("metadata.{}", key);
+                if !KNOWN_METADATA_KEYS.contains(&key.as_str()) {
+                    diagnostics.push(FrameDiagnostic::warning(source, &field, "Unknown metadata key."));
+                } else if ["enable_transform", "active_transform", "active", "enable_mesh"].contains(&key.as_str())
+                    && !value.is_boolean()
+                {
+                    diagnostics.push(FrameDiagnostic::error(source, &field, "Should be a boolean."));
+                }
             }
         }
-        Err(e) => {
-            log::warn!(target: "r2r_transforms",
-                concat!(
-                    "Opening json file failed with: '{}'. ",
-                    "Please check if the file path is correct and ",
-                    "you have sufficient permissions."
-                ),
-                e
-            );
-            None
+        Some(_) => diagnostics.push(FrameDiagnostic::error(source, "metadata", "Should be an object.")),
+    }
+
+    for key in object.keys() {
+        if !["child_frame_id", "parent_frame_id", "transform", "metadata"].contains(&key.as_str()) {
+            diagnostics.push(FrameDiagnostic::warning(source, key, "Unknown field."));
         }
     }
+
+    diagnostics
 }
 
-fn extract_string_field(json: &Value, field: &str) -> Option<String> {
-    match json.get(field).and_then(|v| v.as_str()) {
-        Some(value) => Some(value.to_string()),
-        None => {
-            log::warn!(target: "r2r_transforms",
-                concat!(
-                    "Invalid or missing '{}'. ",
-                    "Ensure the '{}' field is present and ",
-                    "is a valid string."
-                ),
-                field, field
-            );
+// Hand-written quaternions are rarely normalized to more than a few digits, so a small deviation
+// is only a warning, the quaternion is normalized when it is loaded.
+fn rotation_diagnostic(source: &str, rotation: &JsonRotation) -> Option<FrameDiagnostic> {
+    let error = |message: String| Some(FrameDiagnostic::error(source, "transform.rotation", &message));
+    let values: Vec<f64> = match rotation {
+        JsonRotation::Quaternion { x, y, z, w } => vec![*x, *y, *z, *w],
+        JsonRotation::Rpy { roll, pitch, yaw, .. } => vec![*roll, *pitch, *yaw],
+        JsonRotation::AxisAngle { axis, angle, .. } => vec![axis[0], axis[1], axis[2], *angle],
+        JsonRotation::Matrix { matrix } => matrix.iter().flatten().copied().collect(),
+    };
+    if values.iter().any(|value| !value.is_finite()) {
+        return error("The rotation should only contain finite numbers.".to_string());
+    }
+    match rotation {
+        JsonRotation::Quaternion { .. } => {
+            let norm = values.iter().map(|q| q * q).sum::<f64>().sqrt();
+            let message = format!("The quaternion is not normalized, its norm is {}.", norm);
+            if (norm - 1.0).abs() > 1e-3 {
+                error(message)
+            } else if (norm - 1.0).abs() > 1e-6 {
+                Some(FrameDiagnostic::warning(source, "transform.rotation", &message))
+            } else {
+                None
+            }
+        }
+        JsonRotation::Rpy { .. } => None,
+        JsonRotation::AxisAngle { axis, .. } => if axis.iter().map(|a| a * a).sum::<f64>() < 1e-12 {
+            error("The rotation axis should not be zero.".to_string())
+        } else {
             None
+        },
+        JsonRotation::Matrix { matrix } => {
+            let matrix = nalgebra::Matrix3::from_fn(|row, column| matrix[row][column]);
+            let orthonormal = (matrix * matrix.transpose() - nalgebra::Matrix3::identity()).norm() < 1e-6;
+            if !orthonormal || (matrix.determinant() - 1.0).abs() > 1e-6 {
+                error("The rotation matrix should be orthonormal with determinant 1.".to_string())
+            } else {
+                None
+            }
         }
     }
 }
 
-fn extract_transform(json: &Value) -> Option<JsonTransform> {
-    match json.get("transform") {
-        Some(value) => match serde_json::from_value(value.clone()) {
-            Ok(transform) => Some(transform),
-            Err(e) => {
-                log::warn!(target: "r2r_transforms",
+fn load_json_from_file(path: &str) -> Result<Value, TransformError> {
+    match File::open(path) {
+        Ok(file) => {
+            let reader = BufReader::new(file);
+            serde_json::from_reader(reader).map_err(|e| {
+                TransformError::Parse(format!(
                     concat!(
-                        "Failed to deserialize 'transform' field: '{}'. ",
-                        "Ensure the 'transform' field is correctly formatted."
+                        "Deserialization of '{}' failed with: '{}'. ",
+                        "The JSON file may be malformed or contain ",
+                        "unexpected data."
                     ),
-                    e
-                );
-                None
-            }
-        },
-        None => {
-            log::warn!(target: "",
+                    path, e
+                ))
+            })
+        }
+        Err(e) => Err(TransformError::Io(format!(
+            concat!(
+                "Opening json file '{}' failed with: '{}'. ",
+                "Please check if the file path is correct and ",
+                "you have sufficient permissions."
+            ),
+            path, e
+        ))),
+    }
+}
+
+fn extract_string_field(path: &str, json: &Value, field: &str) -> Result<String, TransformError> {
+    match json.get(field).and_then(|v| v.as_str()) {
+        Some(value) => Ok(value.to_string()),
+        None => Err(TransformError::Parse(format!(
+            concat!(
+                "Invalid or missing '{}' in '{}'. ",
+                "Ensure the '{}' field is present and ",
+                "is a valid string."
+            ),
+            field, path, field
+        ))),
+    }
+}
+
+fn extract_transform(path: &str, json: &Value) -> Result<JsonTransform, TransformError> {
+    match json.get("transform") {
+        Some(value) => serde_json::from_value(value.clone()).map_err(|e| {
+            TransformError::Parse(format!(
                 concat!(
-                    "Missing 'transform' field. ",
-                    "Ensure the 'transform' field is present in the JSON."
-                )
-            );
-            None
+                    "Failed to deserialize 'transform' field in '{}': '{}'. ",
+                    "Ensure the 'transform' field is correctly formatted."
+                ),
+                path, e
+            ))
+        }),
+        None => Err(TransformError::Parse(format!(
+            concat!(
+                "Missing 'transform' field in '{}'. ",
+                "Ensure the 'transform' field is present in the JSON."
+            ),
+            path
+        ))),
+    }
+}
+
+// Write the frames into the scenario directory, one JSON file per frame.
+// A frame overwrites the file it was loaded from, new frames get a file named after them.
+pub fn save_frames(
+    path: &str,
+    frames: &[&TransformStamped],
+    encoding: RotationEncoding,
+) -> Result<(), TransformError> {
+    fs::create_dir_all(path).map_err(|e| {
+        TransformError::Io(format!(
+            "Creating the scenario directory '{}' failed with: '{}'.",
+            path, e
+        ))
+    })?;
+
+    let existing = frame_files_in_dir(path)?;
+    for frame in frames {
+        let file_path = match existing.get(&frame.child_frame_id) {
+            Some(file_path) => file_path.clone(),
+            None => Path::new(path)
+                .join(frame_file_name(&frame.child_frame_id))
+                .to_string_lossy()
+                .to_string(),
+        };
+        save_json_to_file(&file_path, &JsonFrame::from_transform_stamped(frame, encoding))?;
+        log::info!(target: "r2r_transforms", "Saved frame '{}' to '{}'.", frame.child_frame_id, file_path);
+    }
+    Ok(())
+}
+
+// The file name of a new frame file. Anything that could leave the directory or that file systems
+// don't take, like '/' or a leading '.', is percent-escaped. The real name is kept in the JSON.
+pub fn frame_file_name(name: &str) -> String {
+    let mut file_name = String::new();
+    for (index, c) in name.chars().enumerate() {
+        if c.is_ascii_alphanumeric() || c == '-' || c == '_' || (c == '.' && index > 0) {
+            file_name.push(c);
+        } else {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                file_name.push_str(&format!("%{:02X}", byte));
+            }
+        }
+    }
+    format!("{}.json", file_name)
+}
+
+// Delete the files of the frames from the scenario directory, frames without a file are skipped.
+pub fn remove_frame_files(path: &str, names: &[&str]) -> Result<(), TransformError> {
+    if names.is_empty() {
+        return Ok(());
+    }
+    let existing = frame_files_in_dir(path)?;
+    for name in names {
+        if let Some(file_path) = existing.get(*name) {
+            fs::remove_file(file_path).map_err(|e| {
+                TransformError::Io(format!("Removing frame file '{}' failed with: '{}'.", file_path, e))
+            })?;
+            log::info!(target: "r2r_transforms", "Removed frame '{}' file '{}'.", name, file_path);
         }
     }
+    Ok(())
+}
+
+// Write the frames into the scenario directory and delete the files of the frames that are no
+// longer there, so that removed and renamed frames don't come back on the next load.
+// Disabled frames were never loaded, and files that don't parse aren't ours to delete, so both stay.
+pub fn save_scenario(
+    path: &str,
+    frames: &HashMap<String, TransformStamped>,
+    encoding: RotationEncoding,
+) -> Result<(), TransformError> {
+    save_frames(path, &frames.values().collect::<Vec<&TransformStamped>>(), encoding)?;
+    let stale: Vec<String> = list_frames_in_dir(path)?
+        .iter()
+        .filter(|file_path| file_path.ends_with(".json"))
+        .filter_map(|file_path| load_frame_from_file(file_path).ok().flatten())
+        .map(|frame| frame.child_frame_id)
+        .filter(|name| !frames.contains_key(name))
+        .collect();
+    remove_frame_files(path, &stale.iter().map(|name| name.as_str()).collect::<Vec<&str>>())
+}
+
+// Write the whole scenario into a single JSON or YAML file, chosen by the file extension.
+// Frames are sorted by name so that the file diffs nicely.
+pub fn save_scenario_file(
+    path: &str,
+    frames: &HashMap<String, TransformStamped>,
+    encoding: RotationEncoding,
+) -> Result<(), TransformError> {
+    let mut json_frames: Vec<JsonFrame> = frames
+        .values()
+        .map(|frame| JsonFrame::from_transform_stamped(frame, encoding))
+        .collect();
+    json_frames.sort_by(|a, b| a.child_frame_id.cmp(&b.child_frame_id));
+
+    let file = File::create(path).map_err(|e| {
+        TransformError::Io(format!("Creating scenario file '{}' failed with: '{}'.", path, e))
+    })?;
+    let writer = BufWriter::new(file);
+    let result = if is_yaml(path) {
+        serde_yaml::to_writer(writer, &json_frames).map_err(|e| e.to_string())
+    } else {
+        serde_json::to_writer_pretty(writer, &json_frames).map_err(|e| e.to_string())
+    };
+    result.map_err(|e| {
+        TransformError::Io(format!("Serializing the scenario to '{}' failed with: '{}'.", path, e))
+    })
+}
+
+// Single scenario files are recognized by their extension, anything else is a directory
+pub fn is_scenario_file(path: &str) -> bool {
+    path.ends_with(".json") || is_yaml(path)
+}
+
+fn is_yaml(path: &str) -> bool {
+    path.ends_with(".yaml") || path.ends_with(".yml")
+}
+
+// Map the child_frame_id of every frame file in the directory to its path
+fn frame_files_in_dir(path: &str) -> Result<HashMap<String, String>, TransformError> {
+    Ok(list_frames_in_dir(path)?
+        .into_iter()
+        .filter(|file_path| file_path.ends_with(".json"))
+        .filter_map(|file_path| {
+            let json = load_json_from_file(&file_path).ok()?;
+            let child_frame_id = json.get("child_frame_id")?.as_str()?.to_string();
+            Some((child_frame_id, file_path))
+        })
+        .collect())
+}
+
+fn save_json_to_file(path: &str, frame: &JsonFrame) -> Result<(), TransformError> {
+    let file = File::create(path).map_err(|e| {
+        TransformError::Io(format!("Creating json file '{}' failed with: '{}'.", path, e))
+    })?;
+    serde_json::to_writer_pretty(BufWriter::new(file), frame).map_err(|e| {
+        TransformError::Io(format!("Serializing frame to '{}' failed with: '{}'.", path, e))
+    })
 }
 
 #[test]
@@ -227,6 +580,312 @@ fn test_load_and_deserialize_from_file() {
     }
 }
 
+#[test]
+fn test_save_and_load_frames() {
+    let path = std::env::temp_dir()
+        .join(format!("r2r_transforms_save_{}", std::process::id()))
+        .to_string_lossy()
+        .to_string();
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+
+    // An existing file that isn't named after its frame is kept and overwritten
+    fs::write(
+        format!("{}/custom_name.json", path),
+        r#"{"child_frame_id": "a", "parent_frame_id": "world", "transform": {"translation": {"x": 0.0, "y": 0.0, "z": 0.0}, "rotation": {"x": 0.0, "y": 0.0, "z": 0.0, "w": 1.0}}, "metadata": {"mesh": "a.stl"}}"#,
+    )
+    .unwrap();
+
+    let mut frames = load_new_scenario(&list_frames_in_dir(&path).unwrap());
+    let a = frames.get_mut("a").unwrap();
+    a.transform = nalgebra::Isometry3::new(
+        nalgebra::Vector3::new(1.0, 2.0, 3.0),
+        nalgebra::Vector3::new(0.0, 0.0, 0.5),
+    );
+    a.active = false;
+    let mut b = TransformStamped::default();
+    b.parent_frame_id = "a".to_string();
+    b.child_frame_id = "b".to_string();
+    frames.insert("b".to_string(), b);
+    // Names with slashes stay in the directory instead of nesting or escaping it
+    for name in ["robot/base_link", "../outside"] {
+        let mut frame = TransformStamped::default();
+        frame.parent_frame_id = "a".to_string();
+        frame.child_frame_id = name.to_string();
+        frames.insert(name.to_string(), frame);
+    }
+
+    save_scenario(&path, &frames, RotationEncoding::Quaternion).unwrap();
+
+    let mut files = list_frames_in_dir(&path).unwrap();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            format!("{}/%2E.%2Foutside.json", path),
+            format!("{}/b.json", path),
+            format!("{}/custom_name.json", path),
+            format!("{}/robot%2Fbase_link.json", path),
+        ]
+    );
+
+    let loaded = load_new_scenario(&files);
+    assert_eq!(loaded.len(), 4);
+    assert_eq!(loaded["robot/base_link"].parent_frame_id, "a");
+    assert_eq!(loaded["../outside"].parent_frame_id, "a");
+    assert!(!loaded["a"].active);
+    assert_eq!(loaded["a"].metadata["mesh"], "a.stl");
+    assert!((loaded["a"].transform.translation.vector - frames["a"].transform.translation.vector).norm() < 1e-9);
+    assert!(loaded["a"].transform.rotation.angle_to(&frames["a"].transform.rotation) < 1e-9);
+    assert_eq!(loaded["b"].parent_frame_id, "a");
+
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn test_save_scenario_removes_stale_files() {
+    let path = std::env::temp_dir()
+        .join(format!("r2r_transforms_stale_{}", std::process::id()))
+        .to_string_lossy()
+        .to_string();
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    let frame = |child: &str, metadata: &str| {
+        format!(
+            r#"{{"child_frame_id": "{child}", "parent_frame_id": "world", "transform": {{"translation": {{"x": 0.0, "y": 0.0, "z": 0.0}}, "rotation": {{"x": 0.0, "y": 0.0, "z": 0.0, "w": 1.0}}}}, "metadata": {metadata}}}"#
+        )
+    };
+    fs::write(format!("{}/a.json", path), frame("a", "{}")).unwrap();
+    fs::write(format!("{}/c.json", path), frame("c", "{}")).unwrap();
+    fs::write(format!("{}/d.json", path), frame("d", r#"{"enable_transform": false}"#)).unwrap();
+
+    let server = SpaceTreeServer::new("test");
+    server.load_scenario(&path, false).unwrap();
+    server.apply_changes();
+    server.rename_transform("a", "b").unwrap();
+    server.remove_transform("c").unwrap();
+    server.apply_changes();
+    server.save_scenario(&path).unwrap();
+
+    // The disabled frame keeps its file
+    let mut files = list_frames_in_dir(&path).unwrap();
+    files.sort();
+    assert_eq!(files, vec![format!("{}/b.json", path), format!("{}/d.json", path)]);
+    let loaded = load_new_scenario(&files);
+    assert_eq!(loaded.keys().collect::<Vec<&String>>(), vec!["b"]);
+
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn test_save_and_load_scenario_file() {
+    let dir = std::env::temp_dir().join(format!("r2r_transforms_file_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR is not set");
+    let frames = load_scenario_from_path(&format!("{}/examples/data", manifest_dir)).unwrap();
+
+    for name in ["scenario.json", "scenario.yaml"] {
+        let path = dir.join(name).to_string_lossy().to_string();
+        assert!(is_scenario_file(&path));
+        save_scenario_file(&path, &frames, RotationEncoding::Quaternion).unwrap();
+        let loaded = load_scenario_from_path(&path).unwrap();
+        assert_eq!(loaded.len(), frames.len());
+        for (name, frame) in &frames {
+            assert_eq!(loaded[name].parent_frame_id, frame.parent_frame_id);
+            assert_eq!(loaded[name].active, frame.active);
+            assert!((loaded[name].transform.translation.vector - frame.transform.translation.vector).norm() < 1e-9);
+        }
+    }
+
+    // A map of frames can leave out the child_frame_id
+    let path = dir.join("map.yml").to_string_lossy().to_string();
+    fs::write(
+        &path,
+        concat!(
+            "table:\n",
+            "  parent_frame_id: world\n",
+            "  transform:\n",
+            "    translation: {x: 1.0, y: 0.0, z: 0.0}\n",
+            "    rotation: {x: 0.0, y: 0.0, z: 0.0, w: 1.0}\n",
+            "  metadata: {}\n",
+            "broken:\n",
+            "  parent_frame_id: world\n",
+        ),
+    )
+    .unwrap();
+    let loaded = load_scenario_file(&path).unwrap();
+    assert_eq!(loaded.len(), 1);
+    assert_eq!(loaded["table"].transform.translation.x, 1.0);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_load_scenario_strict() {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR is not set");
+    let (frames, warnings) = load_scenario_strict(&format!("{}/examples/data", manifest_dir)).unwrap();
+    assert_eq!(frames.len(), 6);
+    assert!(warnings.iter().all(|d| d.severity == Severity::Warning));
+
+    let path = std::env::temp_dir()
+        .join(format!("r2r_transforms_strict_{}.yaml", std::process::id()))
+        .to_string_lossy()
+        .to_string();
+    fs::write(
+        &path,
+        concat!(
+            "- child_frame_id: a\n",
+            "  parent_frame_id: world\n",
+            "  transform:\n",
+            "    translation: {x: 0.0, y: 0.0, z: 0.0}\n",
+            "    rotation: {x: 0.0, y: 0.0, z: 0.0, w: 2.0}\n",
+            "  metadata: {colour: red}\n",
+            "- child_frame_id: b\n",
+            "  parent_frame_id: world\n",
+            "  transform:\n",
+            "    translation: {x: 0.0, y: 0.0}\n",
+            "    rotation: {x: 0.0, y: 0.0, z: 0.0, w: 1.0}\n",
+            "  metadata: {active_transform: 'yes'}\n",
+            "- child_frame_id: c\n",
+            "  parent_frame_id: world\n",
+            "  transform:\n",
+            "    translation: {x: 0.0, y: 0.0, z: 0.0}\n",
+            "    rotation: {x: 0.0, y: 0.0, z: 0.0, w: 1.0}\n",
+            "- child_frame_id: c\n",
+            "  parent_frame_id: a\n",
+            "  transform:\n",
+            "    translation: {x: 0.0, y: 0.0, z: 0.0}\n",
+            "    rotation: {x: 0.0, y: 0.0, z: 0.0, w: 1.0}\n",
+        ),
+    )
+    .unwrap();
+
+    let diagnostics = match load_scenario_strict(&path) {
+        Err(TransformError::InvalidScenario(diagnostics)) => diagnostics,
+        other => panic!("Expected diagnostics, got {:?}", other),
+    };
+    let fields: Vec<(&str, &str, &Severity)> = diagnostics
+        .iter()
+        .map(|d| (d.path.trim_start_matches(&path), d.field.as_str(), &d.severity))
+        .collect();
+    assert_eq!(
+        fields,
+        vec![
+            ("[0]", "transform.rotation", &Severity::Error),
+            ("[0]", "metadata.colour", &Severity::Warning),
+            ("[1]", "transform.translation.z", &Severity::Error),
+            ("[1]", "metadata.active_transform", &Severity::Error),
+            ("[3]", "child_frame_id", &Severity::Error),
+        ]
+    );
+
+    // The lenient loader skips 'b' and lets the second 'c' replace the first one
+    let frames = load_scenario_file(&path).unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames["c"].parent_frame_id, "a");
+
+    // A hand-written quaternion and an unknown metadata key only give warnings, which are returned
+    fs::write(
+        &path,
+        concat!(
+            "- child_frame_id: a\n",
+            "  parent_frame_id: world\n",
+            "  transform:\n",
+            "    translation: {x: 0.0, y: 0.0, z: 0.0}\n",
+            "    rotation: {x: 0.0, y: 0.0, z: 0.7071, w: 0.7071}\n",
+            "  metadata: {colour: red}\n",
+        ),
+    )
+    .unwrap();
+    let (frames, warnings) = load_scenario_strict(&path).unwrap();
+    assert!((frames["a"].transform.rotation.angle() - std::f64::consts::FRAC_PI_2).abs() < 1e-9);
+    let fields: Vec<(&str, &Severity)> = warnings.iter().map(|d| (d.field.as_str(), &d.severity)).collect();
+    assert_eq!(
+        fields,
+        vec![("transform.rotation", &Severity::Warning), ("metadata.colour", &Severity::Warning)]
+    );
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_rotation_encodings() {
+    let expected = nalgebra::UnitQuaternion::from_euler_angles(0.0, 0.0, std::f64::consts::FRAC_PI_2);
+    for rotation in [
+        r#"{"x": 0.0, "y": 0.0, "z": 0.7071067811865476, "w": 0.7071067811865476}"#,
+        r#"{"roll": 0.0, "pitch": 0.0, "yaw": 1.5707963267948966}"#,
+        r#"{"roll": 0.0, "pitch": 0.0, "yaw": 90.0, "degrees": true}"#,
+        r#"{"axis": [0.0, 0.0, 2.0], "angle": 1.5707963267948966}"#,
+        r#"{"axis": [0.0, 0.0, 1.0], "angle": 90.0, "degrees": true}"#,
+        r#"{"matrix": [[0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]]}"#,
+    ] {
+        let json: Value = serde_json::from_str(&format!(
+            r#"{{"child_frame_id": "a", "parent_frame_id": "world", "transform": {{"translation": {{"x": 0.0, "y": 0.0, "z": 0.0}}, "rotation": {}}}}}"#,
+            rotation
+        ))
+        .unwrap();
+        assert!(validate_frame_json("a.json", &json).is_empty(), "{}", rotation);
+        let frame = frame_from_json("a.json", &json).unwrap().unwrap();
+        assert!(frame.transform.rotation.angle_to(&expected) < 1e-9, "{}", rotation);
+    }
+
+    for rotation in [
+        r#"{"axis": [0.0, 0.0, 0.0], "angle": 1.0}"#,
+        r#"{"matrix": [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, -1.0]]}"#,
+        r#"{"roll": 0.0, "pitch": 0.0}"#,
+    ] {
+        let json: Value = serde_json::from_str(&format!(
+            r#"{{"child_frame_id": "a", "parent_frame_id": "world", "transform": {{"translation": {{"x": 0.0, "y": 0.0, "z": 0.0}}, "rotation": {}}}}}"#,
+            rotation
+        ))
+        .unwrap();
+        let diagnostics = validate_frame_json("a.json", &json);
+        assert_eq!(diagnostics.len(), 1, "{}", rotation);
+        assert_eq!(diagnostics[0].field, "transform.rotation");
+    }
+
+    // The lenient loader doesn't turn invalid rotations into something else either
+    for rotation in [
+        r#"{"axis": [0.0, 0.0, 0.0], "angle": 1.0}"#,
+        r#"{"x": 0.0, "y": 0.0, "z": 0.0, "w": 0.0}"#,
+        r#"{"matrix": [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, -1.0]]}"#,
+        r#"{"matrix": [[2.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]}"#,
+    ] {
+        let json: Value = serde_json::from_str(&format!(
+            r#"{{"child_frame_id": "a", "parent_frame_id": "world", "transform": {{"translation": {{"x": 0.0, "y": 0.0, "z": 0.0}}, "rotation": {}}}}}"#,
+            rotation
+        ))
+        .unwrap();
+        assert!(matches!(frame_from_json("a.json", &json), Err(TransformError::Parse(_))), "{}", rotation);
+    }
+    // YAML can hold a NaN, JSON can't
+    assert!(json_rotation_to_unit_quaternion(&JsonRotation::Quaternion { x: f64::NAN, y: 0.0, z: 0.0, w: 1.0 }).is_err());
+}
+
+#[test]
+fn test_save_scenario_file_with_rotation_encoding() {
+    let path = std::env::temp_dir()
+        .join(format!("r2r_transforms_rpy_{}.yaml", std::process::id()))
+        .to_string_lossy()
+        .to_string();
+    let mut frame = TransformStamped::default();
+    frame.parent_frame_id = "world".to_string();
+    frame.child_frame_id = "a".to_string();
+    frame.transform = nalgebra::Isometry3::new(nalgebra::Vector3::new(1.0, 0.0, 0.0), nalgebra::Vector3::new(0.1, 0.2, 0.3));
+    let frames = HashMap::from([("a".to_string(), frame.clone())]);
+
+    save_scenario_file(&path, &frames, RotationEncoding::RpyDegrees).unwrap();
+    let contents = fs::read_to_string(&path).unwrap();
+    assert!(contents.contains("yaw:") && contents.contains("degrees: true"));
+
+    let loaded = load_scenario_file(&path).unwrap();
+    assert!(loaded["a"].transform.rotation.angle_to(&frame.transform.rotation) < 1e-9);
+
+    fs::remove_file(&path).unwrap();
+}
+
 // pub fn load_overlay_scenario
 
 // pub async fn reload_scenario(
@@ -250,35 +909,3 @@ fn test_load_and_deserialize_from_file() {
 //         Err(e) => extra_error_response(&format!("Reloading the scenario failed with: '{:?}'.", e)),
 //     }
 // }
-
-// async fn persist_frame_change(path: &str, frame: FrameData) -> bool {
-//     match fs::read_dir(path) {
-//         Ok(dir) => dir.for_each(|file| match file {
-//             Ok(entry) => match entry.path().to_str() {
-//                 Some(valid) => match valid.to_string() == format!("{}{}", path, frame.child_frame_id.clone()) {
-//                     true => {
-//                         println!("Changing existing frame {} permanently", frame.child_frame_id.clone());
-//                         match File::open(valid.clone()) {
-//                             Ok(file) =>
-//                         }
-//                         let writer = BufWriter::;
-//                     // }
-//                     },
-//                     false => {}
-//                 }
-//                 None => r2r::log_warn!(NODE_ID, "Path is not valid unicode."),
-//             },
-//             Err(e) => r2r::log_warn!(NODE_ID, "Reading entry failed with '{}'.", e),
-//         }),
-//         Err(e) => {
-//             r2r::log_warn!(
-//                 NODE_ID,
-//                 "Reading the scenario directory failed with: '{}'.",
-//                 e
-//             );
-//             r2r::log_warn!(NODE_ID, "Empty scenario is loaded/reloaded.");
-//             return false
-//         }
-//     }
-//     true
-// }
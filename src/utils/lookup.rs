@@ -1,10 +1,10 @@
-use crate::{is_cyclic_all, TransformStamped};
+use crate::{buffer_at_time, build_children_index, find_cyclic_frame, ChildrenIndex, TransformError, TransformStamped};
 use nalgebra::Isometry3;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Arc, Mutex};
 
-use tokio::time::Instant;
+use std::time::SystemTime;
 
 pub static MAX_TRANSFORM_CHAIN: u64 = 1000;
 
@@ -12,76 +12,284 @@ pub fn isometry_chain_product(vec: Vec<Isometry3<f64>>) -> Isometry3<f64> {
     vec.iter().fold(Isometry3::identity(), |a, &b| a * b)
 }
 
+// Linear interpolation of the translation and SLERP of the rotation, ratio in [0, 1]
+pub fn isometry_interpolation(
+    from: &Isometry3<f64>,
+    to: &Isometry3<f64>,
+    ratio: f64,
+) -> Isometry3<f64> {
+    let translation = from.translation.vector.lerp(&to.translation.vector, ratio);
+    let rotation = match from.rotation.try_slerp(&to.rotation, ratio, 1.0e-9) {
+        Some(rotation) => rotation,
+        // The rotations are opposite, so there is no unique shortest path
+        None => if ratio < 0.5 { from.rotation } else { to.rotation },
+    };
+    Isometry3::from_parts(translation.into(), rotation)
+}
+
+// Interpolate between two samples of the same frame at a time in between them.
+// If the frame was reparented in between, the samples can't be blended and the earlier one is used.
+pub fn interpolate_transforms(
+    before: &TransformStamped,
+    after: &TransformStamped,
+    time: SystemTime,
+) -> TransformStamped {
+    let mut transform = before.clone();
+    transform.time_stamp = time;
+    if before.parent_frame_id != after.parent_frame_id || after.time_stamp <= before.time_stamp {
+        return transform;
+    }
+    let span = after.time_stamp.duration_since(before.time_stamp).unwrap_or_default().as_secs_f64();
+    let elapsed = time.duration_since(before.time_stamp).unwrap_or_default().as_secs_f64();
+    transform.transform = isometry_interpolation(
+        &before.transform,
+        &after.transform,
+        (elapsed / span).clamp(0.0, 1.0),
+    );
+    transform
+}
+
 pub fn lookup_transform_with_root(
     parent_frame_id: &str,
     child_frame_id: &str,
     root_frame_id: &str,
     buffer: &Arc<Mutex<HashMap<String, TransformStamped>>>,
-) -> Option<TransformStamped> {
+) -> Result<TransformStamped, TransformError> {
     let buffer_local = buffer.lock().unwrap().clone();
-    let mut chain = vec![];
-    if !is_cyclic_all(&buffer_local) {
-        match parent_to_root(parent_frame_id, root_frame_id, &buffer_local) {
-            Some(up_chain) => match root_to_child(child_frame_id, root_frame_id, &buffer_local) {
-                Some(down_chain) => {
-                    chain.push(up_chain);
-                    chain.push(down_chain);
-                    let iso_3 = isometry_chain_product(chain);
-                    Some(TransformStamped {
-                        active: buffer_local.get(child_frame_id).unwrap().active,
-                        time_stamp: Instant::now(),
-                        parent_frame_id: parent_frame_id.to_string(),
-                        child_frame_id: child_frame_id.to_string(),
-                        transform: iso_3,
-                        metadata: Value::default()
-                    })
+    lookup_transform_in_buffer(parent_frame_id, child_frame_id, root_frame_id, &buffer_local)
+}
+
+// Look up the transform through the lowest common ancestor of the two frames, following
+// only the parent pointers. This is O(depth) and doesn't need to know the root of the tree,
+// so it works for frames whose root isn't a frame in the buffer, and in a buffer with several trees.
+pub fn lookup_transform_in_tree(
+    parent_frame_id: &str,
+    child_frame_id: &str,
+    buffer: &HashMap<String, TransformStamped>,
+) -> Result<TransformStamped, TransformError> {
+    lookup_transform_in_layers(parent_frame_id, child_frame_id, &[buffer])
+}
+
+// Same as lookup_transform_in_tree, but the frames are looked up in the buffers in order,
+// so that our frames and the external frames don't have to be merged into one buffer first.
+pub fn lookup_transform_in_layers(
+    parent_frame_id: &str,
+    child_frame_id: &str,
+    layers: &[&HashMap<String, TransformStamped>],
+) -> Result<TransformStamped, TransformError> {
+    let get = |name: &str| layers.iter().find_map(|layer| layer.get(name));
+
+    // Every ancestor of the parent (and the parent itself) with the parent's pose in it
+    let mut ancestors: HashMap<String, Isometry3<f64>> = HashMap::new();
+    let mut current = parent_frame_id.to_string();
+    let mut pose = Isometry3::identity();
+    loop {
+        if ancestors.insert(current.clone(), pose).is_some() {
+            return Err(TransformError::CycleDetected(current));
+        }
+        if ancestors.len() as u64 > MAX_TRANSFORM_CHAIN {
+            return Err(TransformError::ChainTooLong {
+                parent_frame_id: parent_frame_id.to_string(),
+                child_frame_id: child_frame_id.to_string(),
+            });
+        }
+        match get(&current) {
+            Some(frame) => {
+                pose = frame.transform * pose;
+                current = frame.parent_frame_id.clone();
+            }
+            None => break,
+        }
+    }
+
+    // Go up from the child until the chain meets one of them
+    let mut visited: HashSet<String> = HashSet::new();
+    let mut current = child_frame_id.to_string();
+    let mut pose = Isometry3::identity();
+    let ancestor_to_parent = loop {
+        if let Some(ancestor_to_parent) = ancestors.get(&current) {
+            break ancestor_to_parent;
+        }
+        if !visited.insert(current.clone()) {
+            return Err(TransformError::CycleDetected(current));
+        }
+        if visited.len() as u64 > MAX_TRANSFORM_CHAIN {
+            return Err(TransformError::ChainTooLong {
+                parent_frame_id: parent_frame_id.to_string(),
+                child_frame_id: child_frame_id.to_string(),
+            });
+        }
+        match get(&current) {
+            Some(frame) => {
+                pose = frame.transform * pose;
+                current = frame.parent_frame_id.clone();
+            }
+            None => {
+                for frame_id in [parent_frame_id, child_frame_id] {
+                    if !layers.iter().any(|layer| is_known_frame(frame_id, layer)) {
+                        return Err(TransformError::FrameNotFound(frame_id.to_string()));
+                    }
                 }
-                None => None,
-            },
-            None => None,
+                return Err(TransformError::Disconnected {
+                    parent_frame_id: parent_frame_id.to_string(),
+                    child_frame_id: child_frame_id.to_string(),
+                });
+            }
+        }
+    };
+
+    Ok(TransformStamped {
+        active: get(child_frame_id)
+            .map(|child| child.active)
+            .unwrap_or(false),
+        time_stamp: SystemTime::now(),
+        parent_frame_id: parent_frame_id.to_string(),
+        child_frame_id: child_frame_id.to_string(),
+        transform: ancestor_to_parent.inverse() * pose,
+        metadata: Value::default()
+    })
+}
+
+// Same as lookup_transform_in_tree, but every frame in the chains is taken from its history,
+// interpolated at the requested time. If the lookup fails, report why a frame on the way up
+// from either frame couldn't be sampled, if that's the reason.
+pub fn lookup_transform_in_tree_at(
+    parent_frame_id: &str,
+    child_frame_id: &str,
+    time: SystemTime,
+    allow_extrapolation: bool,
+    buffer_now: &HashMap<String, TransformStamped>,
+    history: &HashMap<String, VecDeque<TransformStamped>>,
+) -> Result<TransformStamped, TransformError> {
+    let samples = buffer_at_time(
+        buffer_now,
+        history,
+        time,
+        allow_extrapolation,
+    );
+    let buffer_local = samples
+        .iter()
+        .filter_map(|(name, sample)| sample.as_ref().ok().map(|s| (name.clone(), s.clone())))
+        .collect::<HashMap<String, TransformStamped>>();
+
+    match lookup_transform_in_tree(parent_frame_id, child_frame_id, &buffer_local) {
+        Ok(mut transform) => {
+            transform.time_stamp = time;
+            Ok(transform)
+        }
+        Err(e) => {
+            for start in [parent_frame_id, child_frame_id] {
+                let mut current = start.to_string();
+                let mut length = 0;
+                while let Some(frame) = buffer_now.get(&current) {
+                    if let Some(Err(sample_error)) = samples.get(&current) {
+                        return Err(sample_error.clone());
+                    }
+                    length += 1;
+                    if length >= MAX_TRANSFORM_CHAIN {
+                        break;
+                    }
+                    current = frame.parent_frame_id.clone();
+                }
+            }
+            Err(e)
         }
-    } else {
-        None
     }
 }
 
+// Frames that are a key in the buffer, or that other frames refer to as their parent, like a root
+fn is_known_frame(frame_id: &str, buffer: &HashMap<String, TransformStamped>) -> bool {
+    buffer.contains_key(frame_id)
+        || buffer.values().any(|frame| frame.parent_frame_id == frame_id)
+}
+
+pub fn lookup_transform_in_buffer(
+    parent_frame_id: &str,
+    child_frame_id: &str,
+    root_frame_id: &str,
+    buffer_local: &HashMap<String, TransformStamped>,
+) -> Result<TransformStamped, TransformError> {
+    lookup_transform_in_buffer_indexed(
+        parent_frame_id,
+        child_frame_id,
+        root_frame_id,
+        buffer_local,
+        &build_children_index(buffer_local),
+    )
+}
+
+// Same as lookup_transform_in_buffer, with a children index of the buffer that is already at hand
+pub fn lookup_transform_in_buffer_indexed(
+    parent_frame_id: &str,
+    child_frame_id: &str,
+    root_frame_id: &str,
+    buffer_local: &HashMap<String, TransformStamped>,
+    children: &ChildrenIndex,
+) -> Result<TransformStamped, TransformError> {
+    if let Some(frame) = find_cyclic_frame(buffer_local, children) {
+        return Err(TransformError::CycleDetected(frame));
+    }
+
+    let up_chain = parent_to_root(parent_frame_id, root_frame_id, buffer_local)?;
+    let down_chain = root_to_child_indexed(child_frame_id, root_frame_id, buffer_local, children)?;
+    Ok(TransformStamped {
+        active: buffer_local
+            .get(child_frame_id)
+            .map(|child| child.active)
+            .unwrap_or(false),
+        time_stamp: SystemTime::now(),
+        parent_frame_id: parent_frame_id.to_string(),
+        child_frame_id: child_frame_id.to_string(),
+        transform: isometry_chain_product(vec![up_chain, down_chain]),
+        metadata: Value::default()
+    })
+}
+
 // Go upstream to the root
 pub fn parent_to_root(
     parent_frame_id: &str,
     root_frame_id: &str,
     buffer: &HashMap<String, TransformStamped>,
-) -> Option<Isometry3<f64>> {
+) -> Result<Isometry3<f64>, TransformError> {
     let mut current_parent = parent_frame_id.to_string();
     let mut path = vec![];
     let mut length = 0;
 
     if parent_frame_id == root_frame_id {
-        return Some(Isometry3::identity())
+        return Ok(Isometry3::identity())
     }
 
     let res = loop {
         if length >= MAX_TRANSFORM_CHAIN {
-            break None;
+            break Err(TransformError::ChainTooLong {
+                parent_frame_id: root_frame_id.to_string(),
+                child_frame_id: parent_frame_id.to_string(),
+            });
         } else {
-            length = length + 1;
+            length += 1;
             match buffer.get(&current_parent) {
                 Some(parent) => {
                     path.push(parent.transform.inverse());
                     if parent.parent_frame_id == root_frame_id {
-                        break Some(path);
+                        break Ok(path);
                     } else {
                         current_parent = parent.parent_frame_id.to_string();
                     }
                 }
-                None => break None,
+                None if current_parent == parent_frame_id => {
+                    break Err(TransformError::FrameNotFound(parent_frame_id.to_string()))
+                }
+                None => {
+                    break Err(TransformError::Disconnected {
+                        parent_frame_id: root_frame_id.to_string(),
+                        child_frame_id: parent_frame_id.to_string(),
+                    })
+                }
             }
         }
     };
 
-    match res {
-        Some(chain) => Some(isometry_chain_product(chain)),
-        None => None,
-    }
+    res.map(isometry_chain_product)
 }
 
 // BFS to get the path to the child
@@ -89,45 +297,48 @@ pub fn root_to_child(
     child_frame_id: &str,
     root_frame_id: &str,
     buffer: &HashMap<String, TransformStamped>,
-) -> Option<Isometry3<f64>> {
-    let mut length = 0;
-    let mut stack = vec![];
-    get_frame_children(root_frame_id, buffer)
-        .iter()
-        .for_each(|(k, v)| stack.push((k.to_string(), vec![k.to_string()], vec![v.transform])));
+) -> Result<Isometry3<f64>, TransformError> {
+    root_to_child_indexed(child_frame_id, root_frame_id, buffer, &build_children_index(buffer))
+}
 
-    let res = loop {
+// Same as root_to_child, but the children of every frame come from the index.
+// Every reached frame keeps its pose in the root, so the path doesn't have to be stored.
+pub fn root_to_child_indexed(
+    child_frame_id: &str,
+    root_frame_id: &str,
+    buffer: &HashMap<String, TransformStamped>,
+    children: &ChildrenIndex,
+) -> Result<Isometry3<f64>, TransformError> {
+    if child_frame_id == root_frame_id {
+        return Ok(Isometry3::identity())
+    }
+
+    let mut queue = VecDeque::from([(root_frame_id.to_string(), Isometry3::identity(), 0)]);
+    while let Some((frame, pose, length)) = queue.pop_front() {
         if length >= MAX_TRANSFORM_CHAIN {
-            break None;
-        } else {
-            length = length + 1;
-            match stack.pop() {
-                Some((frame, path, chain)) => {
-                    if frame == child_frame_id {
-                        break Some(chain);
-                    } else {
-                        get_frame_children(&frame, buffer)
-                            .iter()
-                            .for_each(|(k, v)| {
-                                let mut prev_path = path.clone();
-                                let mut prev_chain = chain.clone();
-                                prev_path.push(k.clone());
-                                prev_chain.push(v.transform);
-                                stack.insert(
-                                    0,
-                                    (k.to_string(), prev_path.clone(), prev_chain.clone()),
-                                )
-                            })
-                    }
+            return Err(TransformError::ChainTooLong {
+                parent_frame_id: root_frame_id.to_string(),
+                child_frame_id: child_frame_id.to_string(),
+            });
+        }
+        for name in children.get(&frame).into_iter().flatten() {
+            if let Some(transform) = buffer.get(name) {
+                let pose = pose * transform.transform;
+                if name == child_frame_id {
+                    return Ok(pose);
                 }
-                None => break None,
+                queue.push_back((name.clone(), pose, length + 1));
             }
         }
-    };
+    }
 
-    match res {
-        Some(chain) => Some(isometry_chain_product(chain)),
-        None => None,
+    if !buffer.contains_key(child_frame_id) {
+        Err(TransformError::FrameNotFound(child_frame_id.to_string()))
+    } else {
+        Err(TransformError::Disconnected {
+            parent_frame_id: root_frame_id.to_string(),
+            child_frame_id: child_frame_id.to_string(),
+        })
     }
 }
 
@@ -149,9 +360,10 @@ mod tests {
     use nalgebra::{Isometry3, Quaternion, Translation, UnitQuaternion, Vector3};
     use serde_json::Value;
     use utils::lookup::{get_frame_children, lookup_transform_with_root, parent_to_root, root_to_child};
-    use std::collections::HashMap;
+    use std::collections::{HashMap, VecDeque};
     use std::sync::{Arc, Mutex};
-    use tokio::time::Instant;
+    use std::time::Duration;
+    use std::time::SystemTime;
 
     use crate::*;
 
@@ -166,7 +378,7 @@ mod tests {
 
         let result = root_to_child("child", "root", &buffer);
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         let expected_transform = Isometry3::translation(1.0, 0.0, 0.0);
         assert_eq!(transform.translation, expected_transform.translation);
@@ -187,7 +399,7 @@ mod tests {
 
         let result = root_to_child("child", "root", &buffer);
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         let expected_transform = Isometry3::translation(2.0, 1.0, 1.0);
         assert_eq!(transform.translation, expected_transform.translation);
@@ -216,7 +428,7 @@ mod tests {
 
         let result = root_to_child("child", "root", &buffer);
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         let expected_transform = Isometry3::translation(2.0, 2.0, 1.0);
         assert_eq!(transform.translation, expected_transform.translation);
@@ -234,7 +446,7 @@ mod tests {
 
         let result = parent_to_root("child", "root", &buffer);
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         let expected_transform = Isometry3::translation(-1.0, 0.0, 0.0); // Inverse of the translation
         assert_eq!(transform.translation, expected_transform.translation);
@@ -255,7 +467,7 @@ mod tests {
 
         let result = parent_to_root("child", "root", &buffer);
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         let expected_transform = Isometry3::translation(-2.0, -1.0, -1.0); // Inverse of the combined translation
         assert_eq!(transform.translation, expected_transform.translation);
@@ -284,7 +496,7 @@ mod tests {
 
         let result = parent_to_root("child", "root", &buffer);
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         let expected_transform = Isometry3::translation(-2.0, -2.0, -1.0); // Inverse of the chosen path
         assert_eq!(transform.translation, expected_transform.translation);
@@ -310,7 +522,7 @@ mod tests {
 
         let result = lookup_transform_with_root("frame1", "frame3", "root", &buffer);
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         assert_eq!(transform.parent_frame_id, "frame1");
         assert_eq!(transform.child_frame_id, "frame3");
@@ -345,7 +557,7 @@ mod tests {
 
         let result = lookup_transform_with_root("root", "frameD", "root", &buffer);
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         assert_eq!(transform.parent_frame_id, "root");
         assert_eq!(transform.child_frame_id, "frameD");
@@ -384,7 +596,7 @@ mod tests {
 
         let result = lookup_transform_with_root("frame1", "frame4", "root", &buffer);
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         assert_eq!(transform.parent_frame_id, "frame1");
         assert_eq!(transform.child_frame_id, "frame4");
@@ -403,7 +615,7 @@ mod tests {
                 "finger".to_string(),
                 TransformStamped {
                     active: true,
-                    time_stamp: Instant::now(),
+                    time_stamp: SystemTime::now(),
                     child_frame_id: "finger".to_string(),
                     parent_frame_id: "hand".to_string(),
                     transform: Isometry3 {
@@ -421,7 +633,7 @@ mod tests {
                 "hand".to_string(),
                 TransformStamped {
                     active: true,
-                    time_stamp: Instant::now(),
+                    time_stamp: SystemTime::now(),
                     child_frame_id: "hand".to_string(),
                     parent_frame_id: "elbow".to_string(),
                     transform: Isometry3 {
@@ -439,7 +651,7 @@ mod tests {
                 "elbow".to_string(),
                 TransformStamped {
                     active: true,
-                    time_stamp: Instant::now(),
+                    time_stamp: SystemTime::now(),
                     child_frame_id: "elbow".to_string(),
                     parent_frame_id: "shoulder".to_string(),
                     transform: Isometry3 {
@@ -457,7 +669,7 @@ mod tests {
                 "shoulder".to_string(),
                 TransformStamped {
                     active: false,
-                    time_stamp: Instant::now(),
+                    time_stamp: SystemTime::now(),
                     child_frame_id: "shoulder".to_string(),
                     parent_frame_id: "world".to_string(),
                     transform: Isometry3 {
@@ -474,7 +686,7 @@ mod tests {
         ]);
 
         let res = parent_to_root("hand", "world", &test_buffer);
-        assert!(!res.is_none());
+        assert!(res.is_ok());
         println!("{}", res.unwrap());
         // TODO: verify if this is correct and test
     }
@@ -482,7 +694,7 @@ mod tests {
     fn dummy_1_frame() -> TransformStamped {
         TransformStamped {
             active: false,
-            time_stamp: Instant::now(),
+            time_stamp: SystemTime::now(),
             parent_frame_id: "world".to_string(),
             child_frame_id: "dummy_1".to_string(),
             transform: Isometry3::default(),
@@ -493,7 +705,7 @@ mod tests {
     fn dummy_2_frame() -> TransformStamped {
         TransformStamped {
             active: true,
-            time_stamp: Instant::now(),
+            time_stamp: SystemTime::now(),
             parent_frame_id: "dummy_1".to_string(),
             child_frame_id: "dummy_2".to_string(),
             transform: Isometry3::default(),
@@ -504,7 +716,7 @@ mod tests {
     fn dummy_3_frame() -> TransformStamped {
         TransformStamped {
             active: true,
-            time_stamp: Instant::now(),
+            time_stamp: SystemTime::now(),
             parent_frame_id: "dummy_1".to_string(),
             child_frame_id: "dummy_3".to_string(),
             transform: Isometry3::default(),
@@ -586,7 +798,7 @@ mod tests {
     ) -> TransformStamped {
         TransformStamped {
             active: true,
-            time_stamp: Instant::now(),
+            time_stamp: SystemTime::now(),
             parent_frame_id: parent_frame_id.to_string(),
             child_frame_id: child_frame_id.to_string(),
             transform,
@@ -611,7 +823,7 @@ mod tests {
 
         let result = lookup_transform_with_root("parent", "child", "root", &buffer);
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         assert_eq!(transform.parent_frame_id, "parent");
         assert_eq!(transform.child_frame_id, "child");
@@ -623,4 +835,147 @@ mod tests {
             expected_transform.translation
         );
     }
+
+    #[test]
+    fn test_lookup_transform_at_time() {
+        let start = SystemTime::now();
+        let cache_time = Duration::from_secs(10);
+        let mut history = HashMap::<String, VecDeque<TransformStamped>>::new();
+
+        let mut arm = create_transform("root", "arm", Isometry3::translation(1.0, 0.0, 0.0));
+        arm.time_stamp = start;
+        let mut gripper = create_transform("arm", "gripper", Isometry3::translation(0.0, 1.0, 0.0));
+        gripper.time_stamp = start;
+        record_transform_sample(&mut history, &arm, cache_time);
+        record_transform_sample(&mut history, &gripper, cache_time);
+
+        arm.transform = Isometry3::translation(3.0, 0.0, 0.0);
+        arm.time_stamp = start + Duration::from_millis(200);
+        record_transform_sample(&mut history, &arm, cache_time);
+
+        let buffer = HashMap::from([
+            ("arm".to_string(), arm),
+            ("gripper".to_string(), gripper),
+        ]);
+
+        let before = lookup_transform_in_tree_at("root", "gripper", start + Duration::from_millis(100), true, &buffer, &history).unwrap();
+        assert_eq!(before.transform.translation, Isometry3::translation(2.0, 1.0, 0.0).translation);
+        assert_eq!(before.time_stamp, start + Duration::from_millis(100));
+
+        let after = lookup_transform_in_tree_at("root", "gripper", start + Duration::from_millis(300), true, &buffer, &history).unwrap();
+        assert_eq!(after.transform.translation, Isometry3::translation(3.0, 1.0, 0.0).translation);
+
+        assert_eq!(
+            lookup_transform_in_tree_at("root", "gripper", start + Duration::from_millis(300), false, &buffer, &history),
+            Err(TransformError::ExtrapolationIntoFuture { frame_id: "gripper".to_string(), by: Duration::from_millis(300) })
+        );
+
+        // Nothing was known about the frames before they were first sampled.
+        assert!(matches!(
+            lookup_transform_in_tree_at("root", "gripper", start - Duration::from_millis(1), true, &buffer, &history),
+            Err(TransformError::ExtrapolationIntoPast { .. })
+        ));
+    }
+
+    #[test]
+    fn test_interpolate_transforms() {
+        let start = SystemTime::now();
+        let mut before = create_transform("root", "frame", Isometry3::new(Vector3::new(0.0, 0.0, 0.0), Vector3::z() * 0.0));
+        before.time_stamp = start;
+        let mut after = create_transform("root", "frame", Isometry3::new(Vector3::new(2.0, 4.0, 0.0), Vector3::z() * 1.0));
+        after.time_stamp = start + Duration::from_millis(100);
+
+        let quarter = interpolate_transforms(&before, &after, start + Duration::from_millis(25));
+        assert_eq!(quarter.time_stamp, start + Duration::from_millis(25));
+        assert!((quarter.transform.translation.vector - Vector3::new(0.5, 1.0, 0.0)).norm() < 1e-9);
+        assert!((quarter.transform.rotation.angle() - 0.25).abs() < 1e-9);
+
+        after.parent_frame_id = "other".to_string();
+        let reparented = interpolate_transforms(&before, &after, start + Duration::from_millis(75));
+        assert_eq!(reparented.transform, before.transform);
+    }
+
+    #[test]
+    fn test_lookup_errors() {
+        let mut buffer = HashMap::new();
+        buffer.insert(
+            "parent".to_string(),
+            create_transform("root", "parent", Isometry3::translation(1.0, 0.0, 0.0)),
+        );
+        buffer.insert(
+            "island".to_string(),
+            create_transform("other_root", "island", Isometry3::translation(0.0, 1.0, 0.0)),
+        );
+
+        assert_eq!(
+            lookup_transform_in_buffer("parent", "missing", "root", &buffer),
+            Err(TransformError::FrameNotFound("missing".to_string()))
+        );
+        assert_eq!(
+            lookup_transform_in_buffer("missing", "parent", "root", &buffer),
+            Err(TransformError::FrameNotFound("missing".to_string()))
+        );
+        assert_eq!(
+            lookup_transform_in_buffer("parent", "island", "root", &buffer),
+            Err(TransformError::Disconnected {
+                parent_frame_id: "root".to_string(),
+                child_frame_id: "island".to_string()
+            })
+        );
+        assert!(lookup_transform_in_buffer("parent", "root", "root", &buffer).is_ok());
+
+        buffer.insert(
+            "root".to_string(),
+            create_transform("parent", "root", Isometry3::identity()),
+        );
+        assert!(matches!(
+            lookup_transform_in_buffer("parent", "root", "root", &buffer),
+            Err(TransformError::CycleDetected(_))
+        ));
+    }
+
+    #[test]
+    fn test_lookup_through_common_ancestor() {
+        // The root "map" is never given, the lookups only follow the parent pointers
+        let mut buffer = HashMap::new();
+        buffer.insert("base".to_string(), create_transform("map", "base", Isometry3::translation(5.0, 0.0, 0.0)));
+        buffer.insert(
+            "arm".to_string(),
+            create_transform("base", "arm", Isometry3::new(Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.0, 0.0, std::f64::consts::FRAC_PI_2))),
+        );
+        buffer.insert("left".to_string(), create_transform("arm", "left", Isometry3::translation(1.0, 0.0, 0.0)));
+        buffer.insert("right".to_string(), create_transform("arm", "right", Isometry3::translation(0.0, 1.0, 0.0)));
+        buffer.insert("island".to_string(), create_transform("other_root", "island", Isometry3::identity()));
+
+        let sibling = lookup_transform_in_tree("left", "right", &buffer).unwrap();
+        assert!((sibling.transform.translation.vector - Vector3::new(-1.0, 1.0, 0.0)).norm() < 1e-9);
+
+        // The same result as going through the root
+        for (parent, child) in [("left", "right"), ("map", "right"), ("right", "base"), ("arm", "arm")] {
+            let through_ancestor = lookup_transform_in_tree(parent, child, &buffer).unwrap();
+            let through_root = lookup_transform_in_buffer(parent, child, "map", &buffer).unwrap();
+            assert!((through_ancestor.transform.to_homogeneous() - through_root.transform.to_homogeneous()).norm() < 1e-9);
+        }
+
+        assert_eq!(
+            lookup_transform_in_tree("left", "missing", &buffer),
+            Err(TransformError::FrameNotFound("missing".to_string()))
+        );
+        assert_eq!(
+            lookup_transform_in_tree("left", "island", &buffer),
+            Err(TransformError::Disconnected {
+                parent_frame_id: "left".to_string(),
+                child_frame_id: "island".to_string()
+            })
+        );
+
+        // Only cycles on the way up are a problem
+        buffer.insert("a".to_string(), create_transform("b", "a", Isometry3::identity()));
+        buffer.insert("b".to_string(), create_transform("a", "b", Isometry3::identity()));
+        assert!(lookup_transform_in_tree("left", "right", &buffer).is_ok());
+        assert!(matches!(
+            lookup_transform_in_tree("left", "a", &buffer),
+            Err(TransformError::CycleDetected(_))
+        ));
+    }
 }
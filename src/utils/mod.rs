@@ -1,4 +1,11 @@
 pub mod lookup;
+pub mod poses;
 pub mod cycles;
+pub mod events;
+pub mod history;
+pub mod index;
 pub mod loading;
-pub mod treeviz;
\ No newline at end of file
+pub mod treeviz;
+pub mod watcher;
+pub mod undo;
+pub mod urdf;
@@ -0,0 +1,123 @@
+use crate::*;
+use nalgebra::Isometry3;
+use std::collections::{HashMap, HashSet};
+
+// The pose of a frame in the root of its tree, together with that root. The root is
+// the first ancestor that isn't in the buffer. The poses stay valid until a frame above
+// them changes, so repeated lookups of the same frames don't walk up the tree again.
+pub type PoseCache = HashMap<String, (String, Isometry3<f64>)>;
+
+// Walk up from the frame to the first cached frame or the root, and cache the poses of
+// every frame on the way down again. A frame that isn't in the buffer is its own root.
+pub fn cached_root_pose(
+    name: &str,
+    buffer: &HashMap<String, TransformStamped>,
+    cache: &mut PoseCache,
+) -> Result<(String, Isometry3<f64>), TransformError> {
+    let mut chain = vec![];
+    let mut visited = HashSet::new();
+    let mut current = name.to_string();
+    let (root, mut pose) = loop {
+        if let Some(cached) = cache.get(&current) {
+            break cached.clone();
+        }
+        match buffer.get(&current) {
+            Some(transform) => {
+                if !visited.insert(current.clone()) {
+                    return Err(TransformError::CycleDetected(current));
+                }
+                chain.push((current.clone(), transform));
+                current = transform.parent_frame_id.clone();
+            }
+            None => break (current, Isometry3::identity()),
+        }
+    };
+
+    for (frame, transform) in chain.into_iter().rev() {
+        pose *= transform.transform;
+        cache.insert(frame, (root.clone(), pose));
+    }
+    Ok((root, pose))
+}
+
+// The pose of the child in the parent frame and whether the child is active, if both are in the
+// same tree. The two root poses come from the same buffer, so they can't mix two commits.
+pub fn relative_root_pose(
+    parent: &str,
+    child: &str,
+    buffer: &HashMap<String, TransformStamped>,
+    cache: &mut PoseCache,
+) -> Option<(Isometry3<f64>, bool)> {
+    let (parent_root, parent_pose) = cached_root_pose(parent, buffer, cache).ok()?;
+    let (child_root, child_pose) = cached_root_pose(child, buffer, cache).ok()?;
+    let active = buffer.get(child).map(|transform| transform.active).unwrap_or(false);
+    (parent_root == child_root).then(|| (parent_pose.inverse() * child_pose, active))
+}
+
+// Drop the cached poses of the frame and of everything below it. A frame is only cached
+// if the frames above it are, so the walk stops at frames that aren't cached.
+pub fn invalidate_subtree(cache: &mut PoseCache, name: &str, children: &ChildrenIndex) {
+    cache.remove(name);
+    let mut stack: Vec<&str> = children.get(name).into_iter().flatten().map(|n| n.as_str()).collect();
+    while let Some(frame) = stack.pop() {
+        if cache.remove(frame).is_some() {
+            stack.extend(children.get(frame).into_iter().flatten().map(|n| n.as_str()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use nalgebra::Isometry3;
+    use std::collections::HashMap;
+
+    use crate::*;
+
+    fn frame(parent: &str, child: &str, x: f64) -> TransformStamped {
+        let mut transform = TransformStamped::default();
+        transform.parent_frame_id = parent.to_string();
+        transform.child_frame_id = child.to_string();
+        transform.transform = Isometry3::translation(x, 0.0, 0.0);
+        transform
+    }
+
+    #[test]
+    fn test_cached_root_pose() {
+        let mut buffer: HashMap<String, TransformStamped> = [
+            frame("world", "a", 1.0),
+            frame("a", "b", 2.0),
+            frame("b", "c", 3.0),
+            frame("a", "d", 4.0),
+        ]
+        .into_iter()
+        .map(|transform| (transform.child_frame_id.clone(), transform))
+        .collect();
+        let mut cache = PoseCache::new();
+
+        let (root, pose) = cached_root_pose("c", &buffer, &mut cache).unwrap();
+        assert_eq!(root, "world");
+        assert_eq!(pose.translation.vector.x, 6.0);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cached_root_pose("world", &buffer, &mut cache).unwrap().0, "world");
+
+        // Moving b only drops b and c, a stays cached
+        cached_root_pose("d", &buffer, &mut cache).unwrap();
+        invalidate_subtree(&mut cache, "b", &build_children_index(&buffer));
+        assert!(cache.contains_key("a") && cache.contains_key("d"));
+        assert!(!cache.contains_key("b") && !cache.contains_key("c"));
+        buffer.insert("b".to_string(), frame("a", "b", 10.0));
+        assert_eq!(cached_root_pose("c", &buffer, &mut cache).unwrap().1.translation.vector.x, 14.0);
+
+        let (pose, active) = relative_root_pose("d", "c", &buffer, &mut cache).unwrap();
+        assert_eq!(pose.translation.vector.x, 9.0);
+        assert!(active);
+        assert!(relative_root_pose("c", "other", &buffer, &mut cache).is_none());
+
+        buffer.insert("a".to_string(), frame("c", "a", 0.0));
+        assert!(matches!(
+            cached_root_pose("c", &buffer, &mut PoseCache::new()),
+            Err(TransformError::CycleDetected(_))
+        ));
+    }
+}
@@ -30,38 +30,64 @@ pub fn build_tree_recursive(
     tree
 }
 
-// pub fn build_all_trees_recursive(
-//     node_id: &str,
-//     transforms: &HashMap<String, TransformStamped>,
-//     parent_map: &HashMap<String, Vec<String>>,
-//     current_depth: u64,
-// ) -> Vec<Tree<String>> {
-
-//     let mut parent_map: HashMap<String, Vec<String>> = HashMap::new();
-//     for transform in transforms.values() {
-//         parent_map
-//             .entry(transform.parent_frame_id.clone())
-//             .or_default()
-//             .push(transform.child_frame_id.clone());
-//     }
-
-// }
+// One tree for every root in the buffer, in the order of get_tree_roots.
+pub fn build_all_trees(
+    transforms: &HashMap<String, TransformStamped>,
+    children: &ChildrenIndex,
+) -> Vec<Tree<String>> {
+    get_tree_roots(transforms)
+        .iter()
+        .map(|root| build_tree_recursive(root, transforms, children, 0))
+        .collect()
+}
 
+// The first root by name, for buffers that hold a single tree.
 pub fn get_tree_root(buffer: &HashMap<String, TransformStamped>) -> Option<String> {
-    if let Some(start_frame) = buffer.keys().next() {
-        let mut current_frame = start_frame.clone();
-        while let Some(transform) = buffer.get(&current_frame) {
-            if !buffer.contains_key(&transform.parent_frame_id) {
-                return Some(transform.parent_frame_id.clone());
-            }
-            current_frame = transform.parent_frame_id.clone();
+    get_tree_roots(buffer).into_iter().next()
+}
+
+// The buffer can hold several disconnected trees. Their roots are the frames that are
+// parents of other frames but have no parent themselves, sorted by name.
+pub fn get_tree_roots(buffer: &HashMap<String, TransformStamped>) -> Vec<String> {
+    let mut roots: Vec<String> = buffer
+        .values()
+        .map(|transform| &transform.parent_frame_id)
+        .filter(|parent| !buffer.contains_key(*parent))
+        .cloned()
+        .collect();
+    roots.sort();
+    roots.dedup();
+    roots
+}
+
+// The root of the tree that the frame belongs to, a root is its own root.
+// Unknown frames and frames in a cycle don't have one.
+pub fn get_frame_root(frame_id: &str, buffer: &HashMap<String, TransformStamped>) -> Option<String> {
+    let mut current_frame = frame_id.to_string();
+    let mut length = 0;
+    while let Some(transform) = buffer.get(&current_frame) {
+        length += 1;
+        if length > MAX_TRANSFORM_CHAIN {
+            return None;
         }
+        current_frame = transform.parent_frame_id.clone();
     }
-    None
+    if current_frame == frame_id && !buffer.values().any(|transform| transform.parent_frame_id == frame_id) {
+        return None;
+    }
+    Some(current_frame)
+}
+
+// The root of the tree of every frame in the buffer.
+pub fn get_frame_roots(buffer: &HashMap<String, TransformStamped>) -> HashMap<String, String> {
+    buffer
+        .keys()
+        .filter_map(|frame| get_frame_root(frame, buffer).map(|root| (frame.clone(), root)))
+        .collect()
 }
 
-pub async fn vizualize_tree(
-    buffer: &SpaceTreeServer,
+pub async fn vizualize_tree<S: TransformStore>(
+    buffer: &SpaceTreeServer<S>,
     refresh_rate: u64,
 ) -> Result<(), Box<dyn std::error::Error>> {
     loop {
@@ -70,26 +96,16 @@ pub async fn vizualize_tree(
     }
 }
 
-pub fn visualize_tree_once(
-    buffer: &SpaceTreeServer,
+pub fn visualize_tree_once<S: TransformStore>(
+    buffer: &SpaceTreeServer<S>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let buffer_local = buffer.local_buffer.lock().unwrap().clone();
-
-    let mut parent_map: HashMap<String, Vec<String>> = HashMap::new();
-    for transform in buffer_local.values() {
-        parent_map
-            .entry(transform.parent_frame_id.clone())
-            .or_default()
-            .push(transform.child_frame_id.clone());
-    }
+    let buffer_local = buffer.store.snapshot();
 
-    match get_tree_root(&buffer_local) {
-        Some(root) => println!(
-            "{}",
-            build_tree_recursive(&root, &buffer_local, &parent_map, 0)
-        ),
-        None => println!("No tree root."),
+    let trees = build_all_trees(&buffer_local, &buffer.store.children());
+    if trees.is_empty() {
+        println!("No tree root.");
     }
+    trees.iter().for_each(|tree| println!("{}", tree));
     Ok(())
 }
 
@@ -99,7 +115,7 @@ mod tests {
     use nalgebra::Isometry3;
     use serde_json::Value;
     use std::collections::HashMap;
-    use tokio::time::Instant;
+    use std::time::SystemTime;
 
     use rand::distributions::{Distribution, Uniform};
     use rand::{thread_rng, Rng};
@@ -114,7 +130,7 @@ mod tests {
             "child1".to_string(),
             TransformStamped {
                 active: true,
-                time_stamp: Instant::now(),
+                time_stamp: SystemTime::now(),
                 parent_frame_id: "root".to_string(),
                 child_frame_id: "child1".to_string(),
                 transform: Isometry3::default(),
@@ -125,7 +141,7 @@ mod tests {
             "child2".to_string(),
             TransformStamped {
                 active: true,
-                time_stamp: Instant::now(),
+                time_stamp: SystemTime::now(),
                 parent_frame_id: "child1".to_string(),
                 child_frame_id: "child2".to_string(),
                 transform: Isometry3::default(),
@@ -136,7 +152,7 @@ mod tests {
             "child3".to_string(),
             TransformStamped {
                 active: true,
-                time_stamp: Instant::now(),
+                time_stamp: SystemTime::now(),
                 parent_frame_id: "child1".to_string(),
                 child_frame_id: "child3".to_string(),
                 transform: Isometry3::default(),
@@ -147,7 +163,7 @@ mod tests {
             "child5".to_string(),
             TransformStamped {
                 active: true,
-                time_stamp: Instant::now(),
+                time_stamp: SystemTime::now(),
                 parent_frame_id: "child3".to_string(),
                 child_frame_id: "child5".to_string(),
                 transform: Isometry3::default(),
@@ -159,7 +175,7 @@ mod tests {
             "child4".to_string(),
             TransformStamped {
                 active: true,
-                time_stamp: Instant::now(),
+                time_stamp: SystemTime::now(),
                 parent_frame_id: "root".to_string(),
                 child_frame_id: "child4".to_string(),
                 transform: Isometry3::default(),
@@ -200,7 +216,7 @@ mod tests {
                 child_id.clone(),
                 TransformStamped {
                     active: true,
-                    time_stamp: Instant::now(),
+                    time_stamp: SystemTime::now(),
                     parent_frame_id: parent_id,
                     child_frame_id: child_id.clone(),
                     transform: Isometry3::default(),
@@ -277,5 +293,31 @@ mod tests {
         println!("{}", tree);
     }
 
+    #[test]
+    fn test_forest_roots() {
+        let transforms: HashMap<String, TransformStamped> = [("a", "world"), ("b", "a"), ("c", "cell"), ("d", "cell")]
+            .iter()
+            .map(|(child, parent)| {
+                let mut transform = TransformStamped::default();
+                transform.parent_frame_id = parent.to_string();
+                transform.child_frame_id = child.to_string();
+                (child.to_string(), transform)
+            })
+            .collect();
+
+        assert_eq!(get_tree_roots(&transforms), vec!["cell", "world"]);
+        assert_eq!(get_tree_root(&transforms), Some("cell".to_string()));
+        assert_eq!(get_frame_root("b", &transforms), Some("world".to_string()));
+        assert_eq!(get_frame_root("cell", &transforms), Some("cell".to_string()));
+        assert_eq!(get_frame_root("missing", &transforms), None);
+        assert_eq!(get_frame_roots(&transforms).get("d"), Some(&"cell".to_string()));
+
+        let trees = build_all_trees(&transforms, &build_children_index(&transforms));
+        assert_eq!(trees.len(), 2);
+        assert_eq!(trees[0].root, "cell");
+        assert_eq!(trees[1].root, "world");
+        assert_eq!(trees[1].leaves[0].leaves[0].root, "b");
+    }
+
     // TODO: need a test for the async function
 }
@@ -0,0 +1,112 @@
+use crate::*;
+use std::collections::{HashMap, VecDeque};
+use std::time::SystemTime;
+
+// The frames that differ between the two buffers, as they were before and after.
+// Returns None if nothing changed, so that no-op batches don't end up on the undo stack.
+pub fn buffer_diff(
+    old_buffer: &HashMap<String, TransformStamped>,
+    new_buffer: &HashMap<String, TransformStamped>,
+) -> Option<BufferDiff> {
+    let mut diff = BufferDiff::default();
+    for name in old_buffer.keys().chain(new_buffer.keys()) {
+        let before = old_buffer.get(name);
+        let after = new_buffer.get(name);
+        if before != after && !diff.before.contains_key(name) {
+            diff.before.insert(name.clone(), before.cloned());
+            diff.after.insert(name.clone(), after.cloned());
+        }
+    }
+    if diff.before.is_empty() {
+        None
+    } else {
+        Some(diff)
+    }
+}
+
+// Put the frames back into the given state, removing the ones that didn't exist.
+// Restored frames are stamped now, since this is when they change again.
+pub fn restore_buffer_state(
+    buffer: &mut HashMap<String, TransformStamped>,
+    state: &HashMap<String, Option<TransformStamped>>,
+) {
+    for (name, transform) in state {
+        match transform {
+            Some(transform) => {
+                let mut restored = transform.clone();
+                restored.time_stamp = SystemTime::now();
+                buffer.insert(name.clone(), restored);
+            }
+            None => {
+                buffer.remove(name);
+            }
+        }
+    }
+}
+
+// Push a diff on the undo stack, dropping the oldest ones beyond the depth.
+pub fn push_undo(undo_stack: &mut VecDeque<BufferDiff>, diff: BufferDiff, depth: usize) {
+    undo_stack.push_back(diff);
+    while undo_stack.len() > depth {
+        undo_stack.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use nalgebra::Isometry3;
+    use std::collections::{HashMap, VecDeque};
+
+    use crate::*;
+
+    fn frame(child: &str, x: f64) -> TransformStamped {
+        let mut transform = TransformStamped::default();
+        transform.parent_frame_id = "world".to_string();
+        transform.child_frame_id = child.to_string();
+        transform.transform = Isometry3::translation(x, 0.0, 0.0);
+        transform
+    }
+
+    #[test]
+    fn test_buffer_diff_and_restore() {
+        let old = HashMap::from([
+            ("a".to_string(), frame("a", 1.0)),
+            ("b".to_string(), frame("b", 2.0)),
+        ]);
+        let mut new = old.clone();
+        new.remove("a");
+        new.insert("c".to_string(), frame("c", 3.0));
+
+        assert_eq!(buffer_diff(&old, &old), None);
+
+        let diff = buffer_diff(&old, &new).unwrap();
+        assert_eq!(diff.before.len(), 2);
+        assert_eq!(diff.before["c"], None);
+        assert_eq!(diff.after["a"], None);
+
+        let mut buffer = new.clone();
+        restore_buffer_state(&mut buffer, &diff.before);
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer["a"].transform, old["a"].transform);
+        assert!(!buffer.contains_key("c"));
+
+        restore_buffer_state(&mut buffer, &diff.after);
+        assert_eq!(buffer.len(), 2);
+        assert!(buffer.contains_key("c"));
+    }
+
+    #[test]
+    fn test_undo_depth() {
+        let mut undo_stack = VecDeque::new();
+        for i in 0..5 {
+            let diff = BufferDiff {
+                before: HashMap::from([(i.to_string(), None)]),
+                after: HashMap::new(),
+            };
+            push_undo(&mut undo_stack, diff, 3);
+        }
+        assert_eq!(undo_stack.len(), 3);
+        assert!(undo_stack[0].before.contains_key("2"));
+    }
+}
@@ -0,0 +1,318 @@
+use crate::*;
+use nalgebra::{Isometry3, Translation3, UnitQuaternion};
+use serde_json::{json, Value};
+use std::{collections::HashMap, fs};
+use std::time::SystemTime;
+
+// Load the joints of a URDF file as frames, see parse_urdf.
+pub fn load_urdf(path: &str) -> Result<HashMap<String, TransformStamped>, TransformError> {
+    let xml = fs::read_to_string(path).map_err(|e| {
+        TransformError::Io(format!("Reading the URDF file '{}' failed with: '{}'.", path, e))
+    })?;
+    parse_urdf(&xml)
+}
+
+// Every joint becomes the frame of its child link, placed at the joint origin in the
+// parent link. Fixed joints are static and all other joints are active. The joint's
+// name, type, axis, limits and origin are kept in the metadata under "joint".
+pub fn parse_urdf(xml: &str) -> Result<HashMap<String, TransformStamped>, TransformError> {
+    let document = roxmltree::Document::parse(xml)
+        .map_err(|e| TransformError::Parse(format!("Parsing the URDF failed with: '{}'.", e)))?;
+    let robot = document.root_element();
+    if !robot.has_tag_name("robot") {
+        return Err(TransformError::Parse(
+            "The URDF root element should be 'robot'.".to_string(),
+        ));
+    }
+
+    let mut frames = HashMap::new();
+    for joint in robot.children().filter(|n| n.has_tag_name("joint")) {
+        let joint_name = required_attribute(&joint, "name")?;
+        let joint_type = required_attribute(&joint, "type")?;
+        let parent = child_attribute(&joint, "parent", "link")
+            .ok_or(TransformError::Parse(format!("Joint '{}' has no parent link.", joint_name)))?;
+        let child = child_attribute(&joint, "child", "link")
+            .ok_or(TransformError::Parse(format!("Joint '{}' has no child link.", joint_name)))?;
+
+        let xyz = parse_vector(&joint_name, child_attribute(&joint, "origin", "xyz"))?;
+        let rpy = parse_vector(&joint_name, child_attribute(&joint, "origin", "rpy"))?;
+        let axis = match child_attribute(&joint, "axis", "xyz") {
+            Some(axis) => parse_vector(&joint_name, Some(axis))?,
+            None => [1.0, 0.0, 0.0],
+        };
+
+        let limit = joint
+            .children()
+            .find(|n| n.has_tag_name("limit"))
+            .map(|limit| {
+                ["lower", "upper", "effort", "velocity"]
+                    .iter()
+                    .filter_map(|key| {
+                        let value = limit.attribute(*key)?.trim().parse::<f64>().ok()?;
+                        Some((key.to_string(), json!(value)))
+                    })
+                    .collect::<serde_json::Map<String, Value>>()
+            });
+
+        let active = joint_type != "fixed";
+        let metadata = json!({
+            "enable_transform": true,
+            "active_transform": active,
+            "joint": {
+                "name": joint_name,
+                "type": joint_type,
+                "axis": axis,
+                "limit": limit,
+                "origin": { "xyz": xyz, "rpy": rpy },
+            }
+        });
+
+        frames.insert(
+            child.clone(),
+            TransformStamped {
+                active,
+                time_stamp: SystemTime::now(),
+                parent_frame_id: parent,
+                child_frame_id: child,
+                transform: origin_to_isometry(xyz, rpy),
+                metadata,
+            },
+        );
+    }
+
+    Ok(frames)
+}
+
+// The URDF origin, translation first and then fixed axis roll, pitch and yaw.
+pub fn origin_to_isometry(xyz: [f64; 3], rpy: [f64; 3]) -> Isometry3<f64> {
+    Isometry3::from_parts(
+        Translation3::new(xyz[0], xyz[1], xyz[2]),
+        UnitQuaternion::from_euler_angles(rpy[0], rpy[1], rpy[2]),
+    )
+}
+
+// The frames imported from the joints of the loaded URDFs by joint name. Every frame is kept
+// with the scope of its URDF, the frame it was attached to, so that robots that have the same
+// joint names can be told apart.
+pub type JointMap = HashMap<String, Vec<(String, TransformStamped)>>;
+
+// Add the joint frames of a URDF, replacing the joints that were loaded into the same scope before
+pub fn add_urdf_joints(joints: &mut JointMap, scope: &str, frames: &HashMap<String, TransformStamped>) {
+    joints.values_mut().for_each(|entries| entries.retain(|(s, _)| s != scope));
+    joints.retain(|_, entries| !entries.is_empty());
+    for frame in frames.values() {
+        if let Some(joint_name) = frame.metadata["joint"]["name"].as_str() {
+            joints
+                .entry(joint_name.to_string())
+                .or_default()
+                .push((scope.to_string(), frame.clone()));
+        }
+    }
+}
+
+// The frame of the joint in the given scope. Without a scope the joint name has to be unique.
+pub fn resolve_joint_frame<'a>(
+    joints: &'a JointMap,
+    joint_name: &str,
+    scope: Option<&str>,
+) -> Result<&'a TransformStamped, TransformError> {
+    let entries = joints.get(joint_name).map(|entries| entries.as_slice()).unwrap_or_default();
+    let mut matching = entries.iter().filter(|(s, _)| scope.is_none_or(|scope| s == scope));
+    match (matching.next(), matching.next()) {
+        (Some((_, frame)), None) => Ok(frame),
+        (None, _) => Err(TransformError::FrameNotFound(joint_name.to_string())),
+        (Some(_), Some(_)) => Err(TransformError::AmbiguousJoint {
+            joint: joint_name.to_string(),
+            scopes: entries.iter().map(|(s, _)| s.clone()).collect(),
+        }),
+    }
+}
+
+// Keep the joint frames in step with a committed diff. A joint frame that was removed, or replaced
+// by a frame of another joint, is dropped, so that the next joint state doesn't move whatever frame
+// took over its name. If a frame with the same joint shows up under another name, which is how
+// a rename looks in the diff, the joint follows it instead.
+pub fn follow_joint_frames(joints: &mut JointMap, diff: &BufferDiff) {
+    let same_joint = |a: &TransformStamped, b: &TransformStamped| a.metadata["joint"] == b.metadata["joint"];
+    let mut moved_in: Vec<&TransformStamped> = diff
+        .after
+        .iter()
+        .filter_map(|(name, frame)| {
+            let frame = frame.as_ref().filter(|frame| !frame.metadata["joint"].is_null())?;
+            let before = diff.before.get(name).cloned().flatten();
+            before.is_none_or(|before| !same_joint(&before, frame)).then_some(frame)
+        })
+        .collect();
+    moved_in.sort_by(|a, b| a.child_frame_id.cmp(&b.child_frame_id));
+    for entries in joints.values_mut() {
+        entries.retain_mut(|(_, frame)| match diff.after.get(&frame.child_frame_id) {
+            None => true,
+            Some(Some(after)) if same_joint(after, frame) => {
+                *frame = after.clone();
+                true
+            }
+            _ => match moved_in.iter().position(|new| same_joint(new, frame)) {
+                Some(position) => {
+                    *frame = moved_in.remove(position).clone();
+                    true
+                }
+                None => false,
+            },
+        });
+    }
+    joints.retain(|_, entries| !entries.is_empty());
+}
+
+// The pose of the joint's child link at the given joint position, using the joint type,
+// axis and origin from the metadata. Revolute and continuous joints rotate about the axis
+// by the position in radians, prismatic joints slide along it by the position in meters.
+pub fn joint_transform(frame: &TransformStamped, position: f64) -> Result<Isometry3<f64>, TransformError> {
+    let joint = &frame.metadata["joint"];
+    let joint_name = joint["name"].as_str().unwrap_or(&frame.child_frame_id).to_string();
+    let invalid = |reason: &str| TransformError::InvalidJoint {
+        joint: joint_name.clone(),
+        reason: reason.to_string(),
+    };
+
+    let vector = |value: &Value| -> Option<[f64; 3]> {
+        let numbers = value.as_array()?;
+        match numbers.iter().map(|n| n.as_f64()).collect::<Option<Vec<f64>>>()?[..] {
+            [x, y, z] => Some([x, y, z]),
+            _ => None,
+        }
+    };
+    let axis = vector(&joint["axis"]).ok_or(invalid("the joint axis is missing from the metadata."))?;
+    let axis = nalgebra::Unit::try_new(nalgebra::Vector3::new(axis[0], axis[1], axis[2]), 1e-12)
+        .ok_or(invalid("the joint axis has zero length."))?;
+    let origin = origin_to_isometry(
+        vector(&joint["origin"]["xyz"]).ok_or(invalid("the joint origin is missing from the metadata."))?,
+        vector(&joint["origin"]["rpy"]).ok_or(invalid("the joint origin is missing from the metadata."))?,
+    );
+
+    let motion = match joint["type"].as_str() {
+        Some("revolute") | Some("continuous") => {
+            Isometry3::from_parts(Translation3::identity(), UnitQuaternion::from_axis_angle(&axis, position))
+        }
+        Some("prismatic") => Isometry3::translation(
+            axis.x * position,
+            axis.y * position,
+            axis.z * position,
+        ),
+        Some(other) => return Err(invalid(&format!("joints of type '{}' can't be moved.", other))),
+        None => return Err(invalid("the joint type is missing from the metadata.")),
+    };
+
+    Ok(origin * motion)
+}
+
+fn required_attribute(node: &roxmltree::Node, attribute: &str) -> Result<String, TransformError> {
+    node.attribute(attribute).map(|value| value.to_string()).ok_or(TransformError::Parse(format!(
+        "A URDF '{}' element is missing the '{}' attribute.",
+        node.tag_name().name(),
+        attribute
+    )))
+}
+
+fn child_attribute(node: &roxmltree::Node, child: &str, attribute: &str) -> Option<String> {
+    node.children()
+        .find(|n| n.has_tag_name(child))
+        .and_then(|n| n.attribute(attribute))
+        .map(|value| value.to_string())
+}
+
+// URDF vectors are three space separated numbers and default to zeros when left out
+fn parse_vector(joint_name: &str, value: Option<String>) -> Result<[f64; 3], TransformError> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok([0.0; 3]),
+    };
+    let numbers = value
+        .split_whitespace()
+        .map(|n| n.parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .ok()
+        .filter(|numbers| numbers.len() == 3)
+        .ok_or(TransformError::Parse(format!(
+            "Joint '{}' has the invalid vector '{}'.",
+            joint_name, value
+        )))?;
+    Ok([numbers[0], numbers[1], numbers[2]])
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::*;
+    use std::f64::consts::PI;
+
+    static URDF: &str = r#"
+        <robot name="arm">
+            <link name="base_link"/>
+            <link name="link_1"/>
+            <link name="tool0"/>
+            <joint name="joint_1" type="revolute">
+                <parent link="base_link"/>
+                <child link="link_1"/>
+                <origin xyz="0 0 0.5" rpy="0 0 1.5707963267948966"/>
+                <axis xyz="0 0 1"/>
+                <limit lower="-3.0" upper="3.0" effort="100" velocity="2.0"/>
+            </joint>
+            <joint name="flange" type="fixed">
+                <parent link="link_1"/>
+                <child link="tool0"/>
+                <origin xyz="0.1 0 0"/>
+            </joint>
+        </robot>
+    "#;
+
+    #[test]
+    fn test_parse_urdf() {
+        let frames = parse_urdf(URDF).unwrap();
+        assert_eq!(frames.len(), 2);
+
+        let link_1 = &frames["link_1"];
+        assert!(link_1.active);
+        assert_eq!(link_1.parent_frame_id, "base_link");
+        assert_eq!(link_1.transform.translation.z, 0.5);
+        assert!((link_1.transform.rotation.angle() - PI / 2.0).abs() < 1e-9);
+        assert_eq!(link_1.metadata["joint"]["type"], "revolute");
+        assert_eq!(link_1.metadata["joint"]["axis"][2], 1.0);
+        assert_eq!(link_1.metadata["joint"]["limit"]["upper"], 3.0);
+
+        let tool0 = &frames["tool0"];
+        assert!(!tool0.active);
+        assert_eq!(tool0.metadata["active_transform"], false);
+        assert_eq!(tool0.transform.translation.x, 0.1);
+        assert!(tool0.metadata["joint"]["limit"].is_null());
+
+        let buffer = frames.clone();
+        let tool_in_base = lookup_transform_in_buffer("base_link", "tool0", "base_link", &buffer).unwrap();
+        assert!((tool_in_base.transform.translation.y - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_joint_transform() {
+        let frames = parse_urdf(URDF).unwrap();
+        let link_1 = &frames["link_1"];
+        assert_eq!(link_1.child_frame_id, "link_1");
+
+        let pose = joint_transform(link_1, PI / 2.0).unwrap();
+        assert_eq!(pose.translation.z, 0.5);
+        assert!((pose.rotation.angle() - PI).abs() < 1e-9);
+
+        let mut slider = link_1.clone();
+        slider.metadata["joint"]["type"] = "prismatic".into();
+        let pose = joint_transform(&slider, 0.25).unwrap();
+        assert!((pose.translation.z - 0.75).abs() < 1e-9);
+
+        assert!(matches!(joint_transform(&frames["tool0"], 1.0), Err(TransformError::InvalidJoint { .. })));
+    }
+
+    #[test]
+    fn test_parse_urdf_errors() {
+        assert!(matches!(parse_urdf("<robot>"), Err(TransformError::Parse(_))));
+        assert!(matches!(parse_urdf("<model/>"), Err(TransformError::Parse(_))));
+        let bad_origin = r#"<robot name="r"><joint name="j" type="fixed"><parent link="a"/><child link="b"/><origin xyz="1 2"/></joint></robot>"#;
+        assert!(matches!(parse_urdf(bad_origin), Err(TransformError::Parse(_))));
+    }
+}
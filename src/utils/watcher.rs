@@ -0,0 +1,288 @@
+use crate::*;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+// The modification time of every frame file in the scenario directory
+pub fn scan_scenario_dir(path: &str) -> Result<HashMap<String, SystemTime>, TransformError> {
+    Ok(list_frames_in_dir(path)?
+        .into_iter()
+        .filter(|file_path| file_path.ends_with(".json"))
+        .filter_map(|file_path| {
+            let modified = fs::metadata(&file_path).and_then(|m| m.modified()).ok()?;
+            Some((file_path, modified))
+        })
+        .collect())
+}
+
+// Turn the changed and deleted files into pending updates. A changed file adds its frame,
+// or replaces it as a whole if it already exists. A deleted file removes the frame it held.
+// file_frames remembers which frame every file holds, so it is updated along the way.
+pub fn reload_updates(
+    changed: &[String],
+    deleted: &[String],
+    file_frames: &mut HashMap<String, String>,
+    existing: &HashSet<String>,
+) -> (Vec<UpdateContext>, ScenarioReloadReport) {
+    let mut updates = vec![];
+    let mut report = ScenarioReloadReport::default();
+    for path in deleted {
+        if let Some(name) = file_frames.remove(path) {
+            queue_remove(name, existing, &mut updates, &mut report);
+        }
+    }
+
+    for path in changed {
+        let frame = match load_frame_from_file(path) {
+            Ok(frame) => frame,
+            Err(e) => {
+                report.failed.push((path.clone(), e));
+                continue;
+            }
+        };
+
+        // The file now holds a different frame, or a disabled one
+        if let Some(old_name) = file_frames.get(path).cloned() {
+            if frame.as_ref().map(|f| &f.child_frame_id) != Some(&old_name) {
+                file_frames.remove(path);
+                queue_remove(old_name, existing, &mut updates, &mut report);
+            }
+        }
+
+        let frame = match frame {
+            Some(frame) => frame,
+            None => continue,
+        };
+        let name = frame.child_frame_id.clone();
+        file_frames.insert(path.clone(), name.clone());
+
+        if existing.contains(&name) && !report.removed.contains(&name) {
+            // The file holds the parent, pose, metadata and active flag, so all of them are taken over
+            updates.push(UpdateContext {
+                name: name.clone(),
+                update_type: UpdateType::Replace,
+                transform: frame,
+            });
+            report.moved.push(name);
+        } else {
+            updates.push(UpdateContext {
+                name: name.clone(),
+                update_type: UpdateType::Add,
+                transform: frame,
+            });
+            report.added.push(name);
+        }
+    }
+
+    (updates, report)
+}
+
+fn queue_remove(
+    name: String,
+    existing: &HashSet<String>,
+    updates: &mut Vec<UpdateContext>,
+    report: &mut ScenarioReloadReport,
+) {
+    if existing.contains(&name) {
+        updates.push(UpdateContext {
+            name: name.clone(),
+            update_type: UpdateType::Remove,
+            transform: TransformStamped::default(),
+        });
+        report.removed.push(name);
+    }
+}
+
+// Poll the scenario directory and queue the changes once the files have been left alone
+// for the debounce time, so that half-written files aren't picked up. Stops when the
+// receiver of the reports is dropped.
+pub async fn watch_scenario_dir<S: TransformStore>(
+    path: &str,
+    debounce: Duration,
+    store: &S,
+    pending_updates: &Arc<Mutex<Vec<UpdateContext>>>,
+    reports: mpsc::UnboundedSender<ScenarioReloadReport>,
+) -> Result<(), TransformError> {
+    let mut known = scan_scenario_dir(path)?;
+    let mut file_frames: HashMap<String, String> = known
+        .keys()
+        .filter_map(|file_path| {
+            let frame = load_frame_from_file(file_path).ok()??;
+            Some((file_path.clone(), frame.child_frame_id))
+        })
+        .collect();
+    let mut settling: Option<(HashMap<String, SystemTime>, Instant)> = None;
+
+    loop {
+        tokio::time::sleep(Duration::from_millis(SCENARIO_POLL_RATE)).await;
+        if reports.is_closed() {
+            return Ok(());
+        }
+
+        let current = match scan_scenario_dir(path) {
+            Ok(current) => current,
+            Err(e) => {
+                log::warn!(target: "r2r_transforms", "Watching the scenario failed with: '{}'.", e);
+                continue;
+            }
+        };
+
+        if current == known {
+            settling = None;
+            continue;
+        }
+
+        match &settling {
+            Some((snapshot, since)) if *snapshot == current => {
+                if since.elapsed() < debounce {
+                    continue;
+                }
+            }
+            _ => {
+                settling = Some((current, Instant::now()));
+                continue;
+            }
+        }
+
+        let changed: Vec<String> = current
+            .iter()
+            .filter(|(file_path, modified)| known.get(*file_path) != Some(*modified))
+            .map(|(file_path, _)| file_path.clone())
+            .collect();
+        let deleted: Vec<String> = known
+            .keys()
+            .filter(|file_path| !current.contains_key(*file_path))
+            .cloned()
+            .collect();
+
+        let buffer = store.snapshot();
+        let mut pending_updates = pending_updates.lock().unwrap();
+        let existing = frames_after_updates(&buffer, &pending_updates);
+        let (updates, report) = reload_updates(&changed, &deleted, &mut file_frames, &existing);
+        log::info!(
+            "Scenario reloaded: added {:?}, moved {:?}, removed {:?}, {} files failed to parse.",
+            report.added, report.moved, report.removed, report.failed.len()
+        );
+        pending_updates.extend(updates);
+        drop(pending_updates);
+
+        known = current;
+        settling = None;
+        if reports.send(report).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
+    use std::time::Duration;
+
+    use crate::*;
+
+    fn write_frame(path: &str, child: &str, parent: &str, x: f64, metadata: &str) {
+        fs::write(
+            path,
+            format!(
+                r#"{{"child_frame_id": "{child}", "parent_frame_id": "{parent}", "transform": {{"translation": {{"x": {x:?}, "y": 0.0, "z": 0.0}}, "rotation": {{"x": 0.0, "y": 0.0, "z": 0.0, "w": 1.0}}}}, "metadata": {metadata}}}"#
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_reload_updates() {
+        let dir = std::env::temp_dir().join(format!("r2r_transforms_reload_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+
+        write_frame(&path("a.json"), "a", "world", 1.0, "{}");
+        write_frame(&path("b.json"), "b", "a", 2.0, r#"{"color": "red"}"#);
+        fs::write(path("broken.json"), "{ not json").unwrap();
+
+        let mut file_frames = HashMap::from([
+            (path("b.json"), "b".to_string()),
+            (path("c.json"), "c".to_string()),
+        ]);
+        let existing = HashSet::from(["b".to_string(), "c".to_string()]);
+
+        let (updates, report) = reload_updates(
+            &[path("a.json"), path("b.json"), path("broken.json")],
+            &[path("c.json")],
+            &mut file_frames,
+            &existing,
+        );
+
+        assert_eq!(report.added, vec!["a"]);
+        assert_eq!(report.moved, vec!["b"]);
+        assert_eq!(report.removed, vec!["c"]);
+        assert_eq!(report.failed.len(), 1);
+        assert!(matches!(report.failed[0].1, TransformError::Parse(_)));
+
+        let update_types: Vec<UpdateType> = updates.iter().map(|u| u.update_type.clone()).collect();
+        assert_eq!(
+            update_types,
+            vec![UpdateType::Remove, UpdateType::Add, UpdateType::Replace]
+        );
+        assert_eq!(updates[2].transform.transform.translation.x, 2.0);
+
+        // The metadata of the frame that already existed is taken over from the file
+        let server = SpaceTreeServer::new("test");
+        let mut old_b = TransformStamped::default();
+        old_b.parent_frame_id = "world".to_string();
+        old_b.child_frame_id = "b".to_string();
+        server.insert_transform("b", old_b).unwrap();
+        server.apply_changes();
+        server.replace_transform("b", updates[2].transform.clone()).unwrap();
+        assert!(server.apply_changes().is_success());
+        let b = server.store.snapshot()["b"].clone();
+        assert_eq!(b.parent_frame_id, "a");
+        assert_eq!(b.transform.translation.x, 2.0);
+        assert_eq!(b.metadata["color"], "red");
+        assert_eq!(file_frames.get(&path("a.json")), Some(&"a".to_string()));
+        assert!(!file_frames.contains_key(&path("c.json")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_watch_scenario() {
+        let dir = std::env::temp_dir().join(format!("r2r_transforms_watch_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        write_frame(&path("a.json"), "a", "world", 1.0, "{}");
+
+        let server = SpaceTreeServer::new("test");
+        server.load_scenario(&dir.to_string_lossy(), false).unwrap();
+        server.apply_changes();
+        let mut reports = server.watch_scenario(&dir.to_string_lossy(), Duration::from_millis(50));
+        // Let the watcher take its first look at the directory before anything changes
+        tokio::time::sleep(Duration::from_millis(2 * SCENARIO_POLL_RATE)).await;
+
+        write_frame(&path("a.json"), "a", "world", 2.0, r#"{"color": "red"}"#);
+        write_frame(&path("b.json"), "b", "a", 3.0, "{}");
+        let report = tokio::time::timeout(Duration::from_secs(5), reports.recv()).await.unwrap().unwrap();
+        assert_eq!(report.moved, vec!["a"]);
+        assert_eq!(report.added, vec!["b"]);
+
+        assert!(server.apply_changes().is_success());
+        let buffer = server.store.snapshot();
+        assert_eq!(buffer["a"].transform.translation.x, 2.0);
+        assert_eq!(buffer["a"].metadata["color"], "red");
+        assert_eq!(buffer["b"].parent_frame_id, "a");
+
+        drop(reports);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}